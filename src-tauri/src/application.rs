@@ -1,6 +1,7 @@
 use crate::{
     application_error::{Error, Result},
-    storage::Storage,
+    downloader::Downloader,
+    storage::{download_history::DownloadHistory, Storage},
     window::{BuildableWindow, MainWindow},
 };
 use parking_lot::{MappedMutexGuard, Mutex, MutexGuard};
@@ -28,6 +29,7 @@ pub struct Application {
     app_handle: AppHandle,
     storage: Mutex<Option<Storage>>,
     is_updating_product: Mutex<bool>,
+    downloader: Downloader,
 }
 
 impl Application {
@@ -44,6 +46,7 @@ impl Application {
             app_handle: app.handle(),
             storage: Mutex::new(Some(Storage::load(app_dir.join("database.db"))?)),
             is_updating_product: Mutex::new(false),
+            downloader: Downloader::new(),
         })
     }
 
@@ -61,6 +64,10 @@ impl Application {
         self.is_updating_product.lock()
     }
 
+    pub fn downloader(&self) -> &Downloader {
+        &self.downloader
+    }
+
     pub fn init(&self) -> Result<()> {
         self.storage.lock().as_ref().unwrap().prepare()?;
         Ok(())
@@ -68,6 +75,9 @@ impl Application {
 
     pub fn run(&self) -> Result<()> {
         MainWindow.build(&self.app_handle)?;
+        DownloadHistory::interrupt_all_in_progress()?;
+        self.downloader.pump()?;
+        self.downloader.start_scheduler();
         Ok(())
     }
 
@@ -1,4 +1,11 @@
+use serde::Serialize;
 use std::path::PathBuf;
+use strum_macros::IntoStaticStr;
+
+#[cfg(windows)]
+static DISK_FULL_OS_ERRORS: [i32; 2] = [39, 112];
+#[cfg(not(windows))]
+static DISK_FULL_OS_ERRORS: [i32; 1] = [28];
 
 pub type Error = ApplicationError;
 pub type Result<T> = std::result::Result<T, Error>;
@@ -28,6 +35,11 @@ pub enum ApplicationError {
         #[from]
         reqwest_error: reqwest::Error,
     },
+    #[error("serde json error: {serde_json_error}")]
+    SerdeJsonError {
+        #[from]
+        serde_json_error: serde_json::Error,
+    },
     #[error("reqwest cookie store error: {reqwest_cookie_store_error}")]
     ReqwestCookieStoreError {
         reqwest_cookie_store_error: Box<dyn std::error::Error + Send + Sync>,
@@ -54,10 +66,12 @@ pub enum ApplicationError {
     ProductArchiveOpenError { io_error: std::io::Error },
     #[error("cannot extract product archive due to: {extract_error}")]
     ProductArchiveExtractError {
-        extract_error: zip_extract::ZipExtractError,
+        extract_error: zip::result::ZipError,
     },
     #[error("cannot delete product archive due to: {io_error}")]
     ProductArchiveDeleteError { io_error: std::io::Error },
+    #[error("cannot write extracted product file due to: {io_error}")]
+    ProductArchiveWriteError { io_error: std::io::Error },
     #[error("cannot cleanup product archive due to: {io_error}")]
     ProductArchiveCleanupError { io_error: std::io::Error },
     #[error("cannot open product path due to: {tauri_error}")]
@@ -72,12 +86,113 @@ pub enum ApplicationError {
     ProductRarArchiveExtractProcessError {
         extract_error: unrar::error::UnrarError,
     },
+    #[error("cannot extract product archive due to: {extract_error}")]
+    ProductSevenZipArchiveExtractError { extract_error: sevenz_rust::Error },
+    #[error("the archive '{file_name}' is password protected and no known password opens it")]
+    ArchivePasswordRequired { file_name: String },
     #[error("the given path is not a valid UTF-8 string: {path}")]
     NonUtf8PathError { path: PathBuf },
+    #[error("cannot download '{file_name}' after {retries} retries due to: {reason}")]
+    ProductDownloadRetryExhausted {
+        file_name: String,
+        retries: u32,
+        reason: String,
+    },
+    #[error("cannot download '{file_name}' because {reason}")]
+    ProductDownloadRejected { file_name: String, reason: String },
+    #[error("the server does not support ranged requests")]
+    ProductDownloadRangeUnsupported,
+    #[error("not enough disk space; {required} bytes are required but only {available} bytes are available")]
+    InsufficientDiskSpace { required: u64, available: u64 },
+    #[error("cannot query available disk space due to: {io_error}")]
+    DiskSpaceQueryError { io_error: std::io::Error },
+    #[error("cannot replace product directory with the downloaded one due to: {io_error}")]
+    ProductStagingCommitError { io_error: std::io::Error },
+    #[error("the product download has been cancelled")]
+    ProductDownloadCancelled,
+    #[error("the product download has been paused")]
+    ProductDownloadPaused,
     #[error("cannot refresh product downloads due to: {io_error}")]
     ProductDownloadRefreshError { io_error: std::io::Error },
 }
 
+#[derive(IntoStaticStr, Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize)]
+pub enum ApplicationErrorKind {
+    Authentication,
+    Rejected,
+    Network,
+    DiskFull,
+    FileSystem,
+    Archive,
+    PasswordRequired,
+    Database,
+    Cancelled,
+    Paused,
+    Other,
+}
+
+impl ApplicationError {
+    pub fn kind(&self) -> ApplicationErrorKind {
+        match self {
+            ApplicationError::AppDirCreationError { io_error }
+            | ApplicationError::ProductDirCreationError { io_error }
+            | ApplicationError::ProductFileCreationError { io_error }
+            | ApplicationError::ProductFileWriteError { io_error }
+            | ApplicationError::ProductStagingCommitError { io_error }
+            | ApplicationError::ProductDownloadRefreshError { io_error }
+            | ApplicationError::DiskSpaceQueryError { io_error } => {
+                if is_disk_full(io_error) {
+                    ApplicationErrorKind::DiskFull
+                } else {
+                    ApplicationErrorKind::FileSystem
+                }
+            }
+            ApplicationError::ProductArchiveOpenError { io_error }
+            | ApplicationError::ProductArchiveDeleteError { io_error }
+            | ApplicationError::ProductArchiveWriteError { io_error }
+            | ApplicationError::ProductArchiveCleanupError { io_error }
+            | ApplicationError::ProductRarArchiveRenameError { io_error } => {
+                if is_disk_full(io_error) {
+                    ApplicationErrorKind::DiskFull
+                } else {
+                    ApplicationErrorKind::Archive
+                }
+            }
+            ApplicationError::InsufficientDiskSpace { .. } => ApplicationErrorKind::DiskFull,
+            ApplicationError::ProductArchiveExtractError { .. }
+            | ApplicationError::ProductRarArchiveExtractOpenError { .. }
+            | ApplicationError::ProductRarArchiveExtractProcessError { .. }
+            | ApplicationError::ProductSevenZipArchiveExtractError { .. }
+            | ApplicationError::NonUtf8PathError { .. } => ApplicationErrorKind::Archive,
+            ApplicationError::ArchivePasswordRequired { .. } => {
+                ApplicationErrorKind::PasswordRequired
+            }
+            ApplicationError::DLsiteCookieNotFound { .. }
+            | ApplicationError::DLsiteNotAuthenticated
+            | ApplicationError::AccountNotExists { .. } => ApplicationErrorKind::Authentication,
+            ApplicationError::ProductDownloadRejected { .. } => ApplicationErrorKind::Rejected,
+            ApplicationError::ReqwestError { .. }
+            | ApplicationError::ReqwestCookieStoreError { .. }
+            | ApplicationError::ProductDownloadRetryExhausted { .. } => {
+                ApplicationErrorKind::Network
+            }
+            ApplicationError::DatabaseError { .. }
+            | ApplicationError::DatabaseCreatedItemNotAccessible
+            | ApplicationError::DatabaseUpdatedItemNotAccessible => ApplicationErrorKind::Database,
+            ApplicationError::ProductDownloadCancelled => ApplicationErrorKind::Cancelled,
+            ApplicationError::ProductDownloadPaused => ApplicationErrorKind::Paused,
+            _ => ApplicationErrorKind::Other,
+        }
+    }
+}
+
+fn is_disk_full(io_error: &std::io::Error) -> bool {
+    io_error
+        .raw_os_error()
+        .map(|code| DISK_FULL_OS_ERRORS.contains(&code))
+        .unwrap_or(false)
+}
+
 impl serde::Serialize for ApplicationError {
     fn serialize<S>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error>
     where
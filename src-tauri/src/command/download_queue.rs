@@ -0,0 +1,41 @@
+use crate::{
+    application::use_application,
+    application_error::Result,
+    downloader::{notify_download_queue_changed, DownloadQueueStatus},
+    storage::download_queue::{EnqueuedDownload, QueuedDownload},
+};
+
+#[tauri::command]
+pub async fn download_queue_list() -> Result<DownloadQueueStatus> {
+    use_application().downloader().status()
+}
+
+#[tauri::command]
+pub async fn download_queue_enqueue(
+    account_id: i64,
+    product_id: String,
+    decompress: Option<bool>,
+    priority: Option<i64>,
+) -> Result<QueuedDownload> {
+    let queued = QueuedDownload::enqueue(EnqueuedDownload {
+        account_id,
+        product_id,
+        decompress: decompress.unwrap_or(true),
+        priority: priority.unwrap_or(0),
+    })?;
+    use_application().downloader().pump()?;
+    notify_download_queue_changed()?;
+    Ok(queued)
+}
+
+#[tauri::command]
+pub async fn download_queue_dequeue(product_id: String) -> Result<()> {
+    use_application().downloader().cancel(&product_id)
+}
+
+#[tauri::command]
+pub async fn download_queue_reorder(product_ids: Vec<String>) -> Result<()> {
+    QueuedDownload::reorder(&product_ids)?;
+    notify_download_queue_changed()?;
+    Ok(())
+}
@@ -3,6 +3,7 @@ use std::path::PathBuf;
 use tauri::{api::path::download_dir, generate_handler, Builder, Runtime};
 
 mod account_management;
+mod download_queue;
 mod latest_product_query;
 mod product;
 mod setting;
@@ -27,12 +28,24 @@ where
             account_management::account_management_update_account,
             account_management::account_management_remove_account,
             account_management::account_management_test_account,
+            download_queue::download_queue_list,
+            download_queue::download_queue_enqueue,
+            download_queue::download_queue_dequeue,
+            download_queue::download_queue_reorder,
             latest_product_query::latest_product_query_get,
             latest_product_query::latest_product_query_set,
             product::product_list_products,
             product::product_download_product,
+            product::product_cancel_download,
+            product::product_pause_download,
+            product::product_resume_download,
+            product::product_list_download_history,
             product::product_open_downloaded_folder,
             product::product_remove_downloaded_product,
+            product::product_set_archive_password,
+            product::product_extract_downloaded,
+            product::product_extract_all_downloaded,
+            product::product_list_files,
             setting::setting_get,
             setting::display_language_setting_get,
             setting::setting_browse_default_root_directory,
@@ -1,25 +1,23 @@
 use super::get_product_download_path;
 use crate::{
+    application::use_application,
     application_error::{Error, Result},
-    dlsite::{download_product, remove_downloaded_product, api},
-    storage::product::{Product, ProductDownload, ProductQuery},
+    dlsite::{
+        api, extractor::ArchiveDisposal, list_file_tree, remove_downloaded_product,
+        remove_staged_product, FileTreeNode,
+    },
+    downloader::{get_extract_options, notify_download_queue_changed},
+    storage::{
+        archive_password::ArchivePassword,
+        download_history::DownloadHistory,
+        download_queue::{EnqueuedDownload, QueuedDownload},
+        product::{Product, ProductQuery},
+        setting::Setting,
+    },
     window::{MainWindow, WindowInfoProvider},
 };
-use serde::Serialize;
 use tauri::{api::shell, Manager, Runtime};
 
-#[derive(Debug, Clone, Serialize)]
-pub struct ProductDownloadProgressEvent<'s> {
-    pub product_id: &'s str,
-    pub progress: usize,
-}
-
-#[derive(Debug, Clone, Serialize)]
-pub struct ProductDownloadEndEvent<'s> {
-    pub product_id: &'s str,
-    pub download: Option<ProductDownload>,
-}
-
 #[tauri::command]
 pub async fn product_list_products(query: Option<ProductQuery>) -> Result<Vec<Product>> {
     let res = Product::list_all(&query.unwrap_or_default()).unwrap();
@@ -33,60 +31,40 @@ pub async fn product_list_products(query: Option<ProductQuery>) -> Result<Vec<Pr
 }
 
 #[tauri::command]
-pub async fn product_download_product<R: Runtime>(
-    app_handle: tauri::AppHandle<R>,
+pub async fn product_download_product(
     account_id: i64,
     product_id: String,
     decompress: Option<bool>,
 ) -> Result<()> {
-    if let Some(window) = app_handle.get_window(&MainWindow.label()) {
-        window.emit("download-begin", &product_id)?;
-    }
-
-    let path = get_product_download_path(&app_handle)?;
-    let download = match download_product(
-        decompress.unwrap_or(true),
+    QueuedDownload::enqueue(EnqueuedDownload {
         account_id,
-        &product_id,
-        &path,
-        |progress, total_progress| {
-            if let Some(window) = app_handle.get_window(&MainWindow.label()) {
-                window.emit(
-                    "download-progress",
-                    ProductDownloadProgressEvent {
-                        product_id: &product_id,
-                        progress: (progress as f64 / total_progress as f64 * 100f64).round()
-                            as usize,
-                    },
-                )?;
-            }
-
-            Ok(())
-        },
-    )
-    .await
-    {
-        Ok(path) => Some(Product::insert_download(
-            &product_id,
-            path.to_str().unwrap(),
-        )?),
-        Err(..) => {
-            remove_downloaded_product(&product_id, &path).ok();
-            None
-        }
-    };
+        product_id,
+        decompress: decompress.unwrap_or(true),
+        priority: 0,
+    })?;
+    use_application().downloader().pump()?;
+    notify_download_queue_changed()?;
+    Ok(())
+}
 
-    if let Some(window) = app_handle.get_window(&MainWindow.label()) {
-        window.emit(
-            "download-end",
-            ProductDownloadEndEvent {
-                product_id: &product_id,
-                download,
-            },
-        )?;
-    }
+#[tauri::command]
+pub async fn product_cancel_download(product_id: String) -> Result<()> {
+    use_application().downloader().cancel(&product_id)
+}
 
-    Ok(())
+#[tauri::command]
+pub async fn product_pause_download(product_id: String) -> Result<()> {
+    use_application().downloader().pause(&product_id)
+}
+
+#[tauri::command]
+pub async fn product_resume_download(product_id: String) -> Result<()> {
+    use_application().downloader().resume(&product_id)
+}
+
+#[tauri::command]
+pub async fn product_list_download_history(product_id: String) -> Result<Vec<DownloadHistory>> {
+    DownloadHistory::list_all(&product_id)
 }
 
 #[tauri::command]
@@ -127,7 +105,9 @@ pub async fn product_remove_downloaded_product<R: Runtime>(
 ) -> Result<()> {
     let path = get_product_download_path(&app_handle)?;
 
-    remove_downloaded_product(&product_id, path).ok();
+    remove_downloaded_product(&product_id, &path).ok();
+    // Archives still waiting for a password are kept in the staging folder.
+    remove_staged_product(&product_id, &path).ok();
     Product::remove_one_download(&product_id)?;
 
     if let Some(window) = app_handle.get_window(&MainWindow.label()) {
@@ -136,3 +116,51 @@ pub async fn product_remove_downloaded_product<R: Runtime>(
 
     Ok(())
 }
+
+#[tauri::command]
+pub async fn product_set_archive_password(product_id: String, password: String) -> Result<()> {
+    ArchivePassword::set_one(&product_id, &password)?;
+
+    // Archives kept back for a missing password are extracted right away with the new one.
+    use_application()
+        .downloader()
+        .extract(vec![product_id], true);
+    Ok(())
+}
+
+#[tauri::command]
+pub async fn product_extract_downloaded(product_id: String) -> Result<()> {
+    use_application()
+        .downloader()
+        .extract(vec![product_id], true);
+    Ok(())
+}
+
+#[tauri::command]
+pub async fn product_extract_all_downloaded() -> Result<()> {
+    use_application()
+        .downloader()
+        .extract(Product::list_all_download_product_ids()?, false);
+    Ok(())
+}
+
+/// Lists the downloaded folder of the product, and the folder its archives are kept in if any.
+#[tauri::command]
+pub async fn product_list_files(product_id: String) -> Result<Vec<FileTreeNode>> {
+    let options = get_extract_options(&Setting::get()?, &product_id)?;
+    let mut trees = Vec::new();
+
+    if let Some(download) = Product::get_one_download(&product_id)? {
+        if download.path.is_dir() {
+            trees.push(list_file_tree(&download.path, &options)?);
+        }
+    }
+
+    if let ArchiveDisposal::MoveTo(path) = &options.archive_disposal {
+        if path.is_dir() {
+            trees.push(list_file_tree(path, &options)?);
+        }
+    }
+
+    Ok(trees)
+}
@@ -1,5 +1,7 @@
 use crate::{
+    application::use_application,
     application_error::Result,
+    downloader::notify_download_queue_changed,
     storage::{display_language_setting::DisplayLanguageSetting, setting::Setting},
     window::{MainWindow, WindowInfoProvider},
 };
@@ -40,6 +42,10 @@ pub async fn setting_save_and_close<R: Runtime>(
     DisplayLanguageSetting::set(&display_language_setting)?;
     window.close()?;
 
+    // Limits, concurrency and schedule windows may have changed.
+    use_application().downloader().pump()?;
+    notify_download_queue_changed()?;
+
     if let Some(window) = app_handle.get_window(&MainWindow.label()) {
         window.emit("display-language-changed", display_language_setting)?;
     }
@@ -364,6 +364,13 @@ pub async fn get_product(
     cookie_store: Arc<CookieStoreMutex>,
     page: usize,
 ) -> Result<Vec<DLsiteProduct>> {
+    Ok(get_product_list(cookie_store, page).await?.works)
+}
+
+pub async fn get_product_list(
+    cookie_store: Arc<CookieStoreMutex>,
+    page: usize,
+) -> Result<DLsiteProductList> {
     let client = ClientBuilder::new()
         .cookie_store(true)
         .cookie_provider(cookie_store)
@@ -378,7 +385,7 @@ pub async fn get_product(
 
     // The body of the response will be a valid json if the login has been succeed.
     match response.json::<DLsiteProductList>().await {
-        Ok(product_list) => Ok(product_list.works),
+        Ok(product_list) => Ok(product_list),
         Err(..) => Err(Error::DLsiteNotAuthenticated),
     }
 }
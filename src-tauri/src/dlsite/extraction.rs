@@ -0,0 +1,613 @@
+use super::{
+    extractor::{
+        get_archive_stem, ArchiveDisposal, ArchiveSet, ArchiveSource, ExtractContext,
+        ExtractOptions, Extractor, ExtractorRegistry, RenamedEntry, SafePathResolver,
+        KEPT_ARCHIVE_DIR_NAME, RENAME_REPORT_FILE_NAME,
+    },
+    progress::ProgressTracker,
+    DownloadPhase, DownloadProgress, DownloadToken,
+};
+use crate::{
+    application_error::{Error, Result},
+    storage::setting::FlattenPolicy,
+};
+use std::{
+    fs::{
+        copy, create_dir_all, read_dir, remove_dir, remove_dir_all, remove_file, rename,
+        OpenOptions,
+    },
+    io::{Result as IOResult, Write},
+    path::{Path, PathBuf},
+};
+
+/// Extracts the archives left in an already downloaded product folder, e.g. after the password of
+/// the archives has been entered. With `use_kept_archives`, the archives kept after an earlier
+/// extraction are extracted again if the product folder has none left.
+pub fn extract_downloaded_product(
+    options: &ExtractOptions,
+    path: impl AsRef<Path>,
+    use_kept_archives: bool,
+    token: &DownloadToken,
+    on_progress: impl Fn(DownloadProgress) -> Result<()> + Send + Sync,
+) -> Result<()> {
+    let path = path.as_ref();
+    let mut archive_path = path;
+    let mut file_names = list_file_names(path)?;
+    // The product folder may hold extracted files next to the archives.
+    let mut source = ArchiveSource::Extracted;
+
+    let kept_path = get_kept_path(&options.archive_disposal, path);
+
+    if let Some(kept_path) = kept_path.as_deref() {
+        if use_kept_archives && !has_archives(path, &file_names) && kept_path.is_dir() {
+            archive_path = kept_path;
+            file_names = list_file_names(kept_path)?;
+            source = ArchiveSource::Product;
+        }
+    }
+
+    let file_names = file_names
+        .iter()
+        .map(|file_name| file_name.as_str())
+        .collect::<Vec<_>>();
+    let tracker = ProgressTracker::new(0, file_names.len(), on_progress);
+
+    tracker.begin_phase(DownloadPhase::Extracting, 0)?;
+    extract_archives_into(
+        options,
+        archive_path,
+        &file_names,
+        source,
+        path,
+        token,
+        &tracker,
+    )
+}
+
+/// Extracts the archives among `file_names` into `path`, disposing of them once all of them
+/// succeed. On failure the archives are left as they are and the partial output is discarded.
+pub fn extract_product(
+    options: &ExtractOptions,
+    file_names: &[&str],
+    path: &Path,
+    token: &DownloadToken,
+    tracker: &ProgressTracker,
+) -> Result<()> {
+    extract_archives_into(
+        options,
+        path,
+        file_names,
+        ArchiveSource::Product,
+        path,
+        token,
+        tracker,
+    )
+}
+
+fn extract_archives_into(
+    options: &ExtractOptions,
+    archive_path: &Path,
+    file_names: &[&str],
+    source: ArchiveSource,
+    path: &Path,
+    token: &DownloadToken,
+    tracker: &ProgressTracker,
+) -> Result<()> {
+    let registry = ExtractorRegistry::default();
+    let archives = registry.find_archives(archive_path, file_names, source);
+
+    if archives.is_empty() {
+        return Ok(());
+    }
+
+    let tmp_path = path.join("__tmp__");
+    // Each archive set gets a folder of its own, so that archives holding the same names do not
+    // overwrite each other and are flattened one by one.
+    let set_paths = (0..archives.len())
+        .map(|index| tmp_path.join(index.to_string()))
+        .collect::<Vec<_>>();
+
+    remove_tmp_dir(path)?;
+
+    let paths = SafePathResolver::new();
+
+    for set_path in &set_paths {
+        create_dir_all(set_path)
+            .map_err(|err| Error::ProductArchiveWriteError { io_error: err })?;
+        paths.set_destination(set_path, path);
+    }
+
+    let context = ExtractContext {
+        options,
+        token,
+        tracker,
+        paths: &paths,
+    };
+
+    if let Err(err) = extract_archives(
+        &registry,
+        &archives,
+        file_names,
+        archive_path,
+        &set_paths,
+        &context,
+    ) {
+        remove_tmp_dir(path)?;
+        return Err(err);
+    }
+
+    tracker.begin_phase(DownloadPhase::CleaningUp, 0)?;
+
+    for set_path in &set_paths {
+        move_extracted_contents(
+            set_path,
+            path,
+            options.flatten_policy,
+            &options.flatten_names,
+            &paths,
+        )?;
+    }
+
+    remove_tmp_dir(path)?;
+
+    // Volumes are only disposed of once everything extracted from them is in place. Those found
+    // outside the product folder are already where they were meant to be kept.
+    if archive_path == path {
+        let volumes = archives
+            .iter()
+            .flat_map(|(_, archive)| &archive.volumes)
+            .collect::<Vec<_>>();
+
+        dispose_volumes(&options.archive_disposal, path, &volumes)?;
+    }
+
+    write_rename_report(path, &paths.renamed_entries())
+}
+
+fn extract_archives(
+    registry: &ExtractorRegistry,
+    archives: &[(&dyn Extractor, ArchiveSet)],
+    file_names: &[&str],
+    archive_path: &Path,
+    set_paths: &[PathBuf],
+    context: &ExtractContext,
+) -> Result<()> {
+    for ((extractor, archive), set_path) in archives.iter().zip(set_paths) {
+        let file_index = file_names
+            .iter()
+            .position(|file_name| *file_name == archive.volumes[0])
+            .unwrap_or_default();
+
+        context
+            .tracker
+            .begin_file(file_index, &archive.volumes[0])?;
+
+        if let Err(err) = extractor.extract(archive_path, archive, set_path, context) {
+            context.token.check()?;
+            return Err(err);
+        }
+
+        extract_nested_archives(registry, set_path, context.options.nested_depth, context)?;
+    }
+
+    Ok(())
+}
+
+/// Extracts the archives found among the extracted files next to them, then the archives found in
+/// those, down to `depth` levels.
+fn extract_nested_archives(
+    registry: &ExtractorRegistry,
+    path: &Path,
+    depth: u32,
+    context: &ExtractContext,
+) -> Result<()> {
+    if depth == 0 {
+        return Ok(());
+    }
+
+    // Directories are listed up front, so that freshly extracted ones are left to the next level.
+    for dir_path in list_dirs(path)? {
+        let file_names = list_file_names(&dir_path)?;
+        let file_names = file_names
+            .iter()
+            .map(|file_name| file_name.as_str())
+            .collect::<Vec<_>>();
+
+        for (extractor, archive) in
+            registry.find_archives(&dir_path, &file_names, ArchiveSource::Extracted)
+        {
+            let target_path = get_unused_path(dir_path.join(get_archive_stem(&archive.volumes[0])));
+            // The extracted files may hold a folder named like the temporary one.
+            let tmp_path = get_unused_path(dir_path.join("__tmp__"));
+
+            create_dir_all(&tmp_path)
+                .map_err(|err| Error::ProductArchiveWriteError { io_error: err })?;
+            context.paths.set_destination(&tmp_path, &target_path);
+            context.tracker.begin_inner_file(&archive.volumes[0])?;
+
+            if let Err(err) = extractor.extract(&dir_path, &archive, &tmp_path, context) {
+                context.token.check()?;
+                return Err(err);
+            }
+
+            create_dir_all(&target_path)
+                .map_err(|err| Error::ProductArchiveWriteError { io_error: err })?;
+            move_extracted_contents(
+                &tmp_path,
+                &target_path,
+                context.options.flatten_policy,
+                &[get_archive_stem(&archive.volumes[0])],
+                context.paths,
+            )?;
+            context.paths.forget(&tmp_path);
+
+            for volume in &archive.volumes {
+                remove_file(dir_path.join(volume))
+                    .map_err(|err| Error::ProductArchiveDeleteError { io_error: err })?;
+            }
+
+            extract_nested_archives(registry, &target_path, depth - 1, context)?;
+        }
+    }
+
+    Ok(())
+}
+
+fn dispose_volumes(disposal: &ArchiveDisposal, path: &Path, volumes: &[&String]) -> Result<()> {
+    match get_kept_path(disposal, path) {
+        Some(kept_path) => {
+            create_dir_all(&kept_path)
+                .map_err(|err| Error::ProductArchiveWriteError { io_error: err })?;
+
+            for volume in volumes {
+                move_file(&path.join(volume), &kept_path.join(volume))
+                    .map_err(|err| Error::ProductArchiveCleanupError { io_error: err })?;
+            }
+        }
+        None => {
+            for volume in volumes {
+                remove_file(path.join(volume))
+                    .map_err(|err| Error::ProductArchiveDeleteError { io_error: err })?;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+fn get_kept_path(disposal: &ArchiveDisposal, path: &Path) -> Option<PathBuf> {
+    match disposal {
+        ArchiveDisposal::Remove => None,
+        ArchiveDisposal::Keep => Some(path.join(KEPT_ARCHIVE_DIR_NAME)),
+        ArchiveDisposal::MoveTo(kept_path) => Some(kept_path.clone()),
+    }
+}
+
+// The archive root may be on another drive, which `rename` cannot move files across.
+fn move_file(from: &Path, to: &Path) -> IOResult<()> {
+    rename(from, to).or_else(|_| copy(from, to).and_then(|_| remove_file(from)))
+}
+
+fn has_archives(path: &Path, file_names: &[String]) -> bool {
+    let file_names = file_names
+        .iter()
+        .map(|file_name| file_name.as_str())
+        .collect::<Vec<_>>();
+
+    !ExtractorRegistry::default()
+        .find_archives(path, &file_names, ArchiveSource::Extracted)
+        .is_empty()
+}
+
+/// Appends the entries that were renamed or skipped to the report in the product folder.
+fn write_rename_report(path: &Path, entries: &[RenamedEntry]) -> Result<()> {
+    if entries.is_empty() {
+        return Ok(());
+    }
+
+    let mut report = String::new();
+
+    for entry in entries {
+        report.push_str(&match &entry.path {
+            Some(renamed_path) => format!(
+                "{}: {} -> {}\n",
+                entry.archive,
+                entry.name,
+                renamed_path
+                    .strip_prefix(path)
+                    .unwrap_or(renamed_path)
+                    .display()
+            ),
+            None => format!("{}: {} (skipped)\n", entry.archive, entry.name),
+        });
+    }
+
+    OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path.join(RENAME_REPORT_FILE_NAME))
+        .and_then(|mut file| file.write_all(report.as_bytes()))
+        .map_err(|err| Error::ProductArchiveWriteError { io_error: err })
+}
+
+fn remove_tmp_dir(path: &Path) -> Result<()> {
+    let tmp_path = path.join("__tmp__");
+
+    if tmp_path.exists() {
+        remove_dir_all(&tmp_path)
+            .map_err(|err| Error::ProductArchiveCleanupError { io_error: err })?;
+    }
+
+    Ok(())
+}
+
+fn list_file_names(path: &Path) -> Result<Vec<String>> {
+    Ok(read_dir(path)
+        .map_err(|err| Error::ProductArchiveCleanupError { io_error: err })?
+        .collect::<IOResult<Vec<_>>>()
+        .map_err(|err| Error::ProductArchiveCleanupError { io_error: err })?
+        .into_iter()
+        .filter(|entry| entry.path().is_file())
+        .filter_map(|entry| entry.file_name().into_string().ok())
+        .collect())
+}
+
+fn list_dirs(path: &Path) -> Result<Vec<PathBuf>> {
+    let mut dir_paths = vec![path.to_owned()];
+    let mut index = 0;
+
+    while index < dir_paths.len() {
+        for entry in read_dir(&dir_paths[index])
+            .map_err(|err| Error::ProductArchiveCleanupError { io_error: err })?
+        {
+            let entry = entry.map_err(|err| Error::ProductArchiveCleanupError { io_error: err })?;
+
+            if entry
+                .file_type()
+                .map_err(|err| Error::ProductArchiveCleanupError { io_error: err })?
+                .is_dir()
+            {
+                dir_paths.push(entry.path());
+            }
+        }
+
+        index += 1;
+    }
+
+    Ok(dir_paths)
+}
+
+fn get_unused_path(path: PathBuf) -> PathBuf {
+    if !path.exists() {
+        return path;
+    }
+
+    let file_name = path
+        .file_name()
+        .unwrap_or_default()
+        .to_string_lossy()
+        .into_owned();
+
+    (2..)
+        .map(|index| path.with_file_name(format!("{} ({})", file_name, index)))
+        .find(|path| !path.exists())
+        .unwrap()
+}
+
+/// Moves everything extracted into `tmp_path` over to `path`, dropping a top-level directory that
+/// holds all of it if the policy allows. `names` are what the directory may be named after.
+fn move_extracted_contents(
+    tmp_path: &Path,
+    path: &Path,
+    policy: FlattenPolicy,
+    names: &[impl AsRef<str>],
+    paths: &SafePathResolver,
+) -> Result<()> {
+    let mut content_paths = read_dir(tmp_path)
+        .map_err(|err| Error::ProductArchiveCleanupError { io_error: err })?
+        .collect::<IOResult<Vec<_>>>()
+        .map_err(|err| Error::ProductArchiveCleanupError { io_error: err })?;
+    let content_prefix_path;
+
+    if content_paths.len() == 1
+        && content_paths[0]
+            .file_type()
+            .map_err(|err| Error::ProductArchiveCleanupError { io_error: err })?
+            .is_dir()
+        && should_flatten(
+            &content_paths[0].file_name().to_string_lossy(),
+            policy,
+            names,
+        )
+    {
+        content_prefix_path = content_paths[0].path();
+        content_paths = read_dir(content_paths[0].path())
+            .map_err(|err| Error::ProductArchiveCleanupError { io_error: err })?
+            .collect::<IOResult<Vec<_>>>()
+            .map_err(|err| Error::ProductArchiveCleanupError { io_error: err })?;
+    } else {
+        content_prefix_path = tmp_path.to_owned();
+    }
+
+    for content_path in content_paths {
+        let content_path = content_path.path();
+
+        merge_into(
+            &content_path,
+            &path.join(content_path.strip_prefix(&content_prefix_path).unwrap()),
+        )
+        .map_err(|err| Error::ProductArchiveCleanupError { io_error: err })?;
+    }
+
+    remove_dir_all(tmp_path).map_err(|err| Error::ProductArchiveCleanupError { io_error: err })?;
+    paths.relocate(&content_prefix_path, path);
+
+    Ok(())
+}
+
+/// Moves `from` to `to`, merging directories that already exist there and replacing anything else,
+/// so that extracting a product again updates the previous output.
+fn merge_into(from: &Path, to: &Path) -> IOResult<()> {
+    if from.is_dir() && to.is_dir() {
+        for entry in read_dir(from)? {
+            let entry = entry?;
+            merge_into(&entry.path(), &to.join(entry.file_name()))?;
+        }
+
+        return remove_dir(from);
+    }
+
+    if to.is_dir() {
+        remove_dir_all(to)?;
+    } else if to.exists() {
+        remove_file(to)?;
+    }
+
+    rename(from, to)
+}
+
+fn should_flatten(dir_name: &str, policy: FlattenPolicy, names: &[impl AsRef<str>]) -> bool {
+    match policy {
+        FlattenPolicy::Never => false,
+        FlattenPolicy::SingleDir => true,
+        FlattenPolicy::MatchingDir => {
+            let dir_name = normalize_name(dir_name);
+
+            !dir_name.is_empty()
+                && names
+                    .iter()
+                    .any(|name| normalize_name(name.as_ref()) == dir_name)
+        }
+    }
+}
+
+// Archivers replace characters that are not allowed in file names, so only letters and digits are
+// compared.
+fn normalize_name(name: &str) -> String {
+    name.chars()
+        .filter(|c| c.is_alphanumeric())
+        .flat_map(char::to_lowercase)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::{read, write, File};
+    use zip::{write::FileOptions, ZipWriter};
+
+    fn write_zip(path: &Path, entries: &[(&str, &[u8])]) {
+        let mut writer = ZipWriter::new(File::create(path).unwrap());
+
+        for (name, data) in entries {
+            writer.start_file(*name, FileOptions::default()).unwrap();
+            writer.write_all(data).unwrap();
+        }
+
+        writer.finish().unwrap();
+    }
+
+    #[test]
+    fn plain_executables_among_extracted_files_are_left_alone() {
+        let dir = tempfile::tempdir().unwrap();
+        let executable = b"MZ\x90\x00\x03\x00\x00\x00this program cannot be run in DOS mode";
+
+        write_zip(&dir.path().join("product.zip"), &[("Game.exe", executable)]);
+
+        let options = ExtractOptions {
+            nested_depth: 1,
+            ..Default::default()
+        };
+        let token = DownloadToken::new();
+        let tracker = ProgressTracker::new(0, 1, |_| Ok(()));
+
+        extract_product(&options, &["product.zip"], dir.path(), &token, &tracker).unwrap();
+
+        assert_eq!(read(dir.path().join("Game.exe")).unwrap(), executable);
+        assert!(!dir.path().join("Game.rar").exists());
+        assert!(!dir.path().join("product.zip").exists());
+    }
+
+    #[test]
+    fn extracting_again_merges_into_existing_contents() {
+        let dir = tempfile::tempdir().unwrap();
+
+        create_dir_all(dir.path().join("data")).unwrap();
+        write(dir.path().join("data").join("a.txt"), b"old").unwrap();
+        write(dir.path().join("data").join("kept.txt"), b"kept").unwrap();
+        write_zip(
+            &dir.path().join("product.zip"),
+            &[("data/a.txt", b"new"), ("data/b.txt", b"added")],
+        );
+
+        let options = ExtractOptions {
+            flatten_policy: FlattenPolicy::Never,
+            ..Default::default()
+        };
+        let token = DownloadToken::new();
+        let tracker = ProgressTracker::new(0, 1, |_| Ok(()));
+
+        extract_product(&options, &["product.zip"], dir.path(), &token, &tracker).unwrap();
+
+        let data_path = dir.path().join("data");
+        assert_eq!(read(data_path.join("a.txt")).unwrap(), b"new");
+        assert_eq!(read(data_path.join("b.txt")).unwrap(), b"added");
+        assert_eq!(read(data_path.join("kept.txt")).unwrap(), b"kept");
+        assert!(!dir.path().join("product.zip").exists());
+        assert!(!dir.path().join("__tmp__").exists());
+    }
+
+    #[test]
+    fn kept_archives_are_extracted_again_only_when_asked_to() {
+        let dir = tempfile::tempdir().unwrap();
+
+        write_zip(&dir.path().join("product.zip"), &[("a.txt", b"a")]);
+
+        let options = ExtractOptions {
+            archive_disposal: ArchiveDisposal::Keep,
+            ..Default::default()
+        };
+        let token = DownloadToken::new();
+        let tracker = ProgressTracker::new(0, 1, |_| Ok(()));
+
+        extract_product(&options, &["product.zip"], dir.path(), &token, &tracker).unwrap();
+
+        assert!(!dir.path().join("product.zip").exists());
+        assert!(dir
+            .path()
+            .join(KEPT_ARCHIVE_DIR_NAME)
+            .join("product.zip")
+            .exists());
+
+        write(dir.path().join("a.txt"), b"changed").unwrap();
+        extract_downloaded_product(&options, dir.path(), false, &token, |_| Ok(())).unwrap();
+        assert_eq!(read(dir.path().join("a.txt")).unwrap(), b"changed");
+
+        extract_downloaded_product(&options, dir.path(), true, &token, |_| Ok(())).unwrap();
+        assert_eq!(read(dir.path().join("a.txt")).unwrap(), b"a");
+        assert!(dir
+            .path()
+            .join(KEPT_ARCHIVE_DIR_NAME)
+            .join("product.zip")
+            .exists());
+    }
+
+    #[test]
+    fn renamed_entries_are_reported_where_they_end_up() {
+        let dir = tempfile::tempdir().unwrap();
+
+        write_zip(&dir.path().join("product.zip"), &[("Game/a?.txt", b"a")]);
+
+        let options = ExtractOptions::default();
+        let token = DownloadToken::new();
+        let tracker = ProgressTracker::new(0, 1, |_| Ok(()));
+
+        extract_product(&options, &["product.zip"], dir.path(), &token, &tracker).unwrap();
+
+        assert_eq!(read(dir.path().join("a_.txt")).unwrap(), b"a");
+        assert_eq!(
+            read(dir.path().join(RENAME_REPORT_FILE_NAME)).unwrap(),
+            b"product.zip: Game/a?.txt -> a_.txt\n"
+        );
+    }
+}
@@ -0,0 +1,383 @@
+mod rar_archive;
+mod safe_path;
+mod seven_zip_archive;
+mod split_zip_archive;
+mod volume_reader;
+mod zip_archive;
+
+pub use rar_archive::RarExtractor;
+pub use safe_path::{RenamedEntry, SafePathResolver, RENAME_REPORT_FILE_NAME};
+pub use seven_zip_archive::SevenZipExtractor;
+pub use split_zip_archive::SplitZipExtractor;
+pub use zip_archive::ZipExtractor;
+
+use super::{progress::ProgressTracker, DownloadToken};
+use crate::{
+    application_error::{Error, Result},
+    storage::setting::{FlattenPolicy, ZipFilenameEncoding},
+};
+use std::{
+    collections::{BTreeMap, HashSet},
+    fs::File,
+    io::Read,
+    path::{Path, PathBuf},
+};
+
+/// Folder archives are kept in after they have been extracted, out of the way of the extracted files.
+pub static KEPT_ARCHIVE_DIR_NAME: &str = ".archives";
+
+static RAR_SIGNATURE: &[u8; 6] = b"Rar!\x1a\x07";
+// unrar looks for the signature this far into self-extracting archives.
+static MAX_SFX_SIZE: u64 = 0x400000;
+
+#[derive(Debug, Clone, Default)]
+pub struct ExtractOptions {
+    pub zip_filename_encoding: Option<ZipFilenameEncoding>,
+    /// How many levels of archives found inside the extracted files get extracted as well.
+    pub nested_depth: u32,
+    /// Tried in order on archives that cannot be extracted without a password.
+    pub passwords: Vec<String>,
+    pub flatten_policy: FlattenPolicy,
+    /// Product ID and titles, one of which a top-level directory must be named after to be
+    /// flattened under [`FlattenPolicy::MatchingDir`].
+    pub flatten_names: Vec<String>,
+    pub archive_disposal: ArchiveDisposal,
+}
+
+/// What happens to the volumes of the archives once all of them have been extracted.
+#[derive(Default, Debug, Clone)]
+pub enum ArchiveDisposal {
+    #[default]
+    Remove,
+    /// Moves them into the [`KEPT_ARCHIVE_DIR_NAME`] folder inside the product folder.
+    Keep,
+    /// Moves them into the given folder, which is also searched when re-extracting the product.
+    MoveTo(PathBuf),
+}
+
+pub struct ExtractContext<'a> {
+    pub options: &'a ExtractOptions,
+    pub token: &'a DownloadToken,
+    pub tracker: &'a ProgressTracker<'a>,
+    pub paths: &'a SafePathResolver,
+}
+
+/// An entry as stored in an archive, with its unpacked size.
+#[derive(Debug, Clone)]
+pub struct ArchiveEntry {
+    pub name: String,
+    pub size: u64,
+    pub is_dir: bool,
+}
+
+/// Files making up a single archive, ordered from the first volume.
+#[derive(Debug, Clone)]
+pub struct ArchiveSet {
+    pub volumes: Vec<String>,
+}
+
+/// Where the files searched for archives come from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ArchiveSource {
+    /// Files of a product as listed by the DLsite, which are archives whenever their extension says
+    /// so.
+    Product,
+    /// Files that were extracted or are otherwise unknown, which also need an archive signature.
+    Extracted,
+}
+
+pub trait Extractor: Send + Sync {
+    /// Picks the archives this extractor can handle out of the files in `path`.
+    fn find_archives(
+        &self,
+        path: &Path,
+        file_names: &[&str],
+        source: ArchiveSource,
+    ) -> Vec<ArchiveSet>;
+
+    fn extract(
+        &self,
+        path: &Path,
+        archive: &ArchiveSet,
+        target_path: &Path,
+        context: &ExtractContext,
+    ) -> Result<()>;
+
+    fn list_entries(
+        &self,
+        path: &Path,
+        archive: &ArchiveSet,
+        options: &ExtractOptions,
+    ) -> Result<Vec<ArchiveEntry>>;
+}
+
+pub struct ExtractorRegistry {
+    extractors: Vec<Box<dyn Extractor>>,
+}
+
+impl ExtractorRegistry {
+    pub fn new() -> Self {
+        Self {
+            extractors: Vec::new(),
+        }
+    }
+
+    pub fn register(&mut self, extractor: impl Extractor + 'static) -> &mut Self {
+        self.extractors.push(Box::new(extractor));
+        self
+    }
+
+    /// Each file is claimed by the first registered extractor that recognizes it.
+    pub fn find_archives(
+        &self,
+        path: &Path,
+        file_names: &[&str],
+        source: ArchiveSource,
+    ) -> Vec<(&dyn Extractor, ArchiveSet)> {
+        let mut claimed = HashSet::new();
+        let mut archives = Vec::new();
+
+        for extractor in &self.extractors {
+            let remaining = file_names
+                .iter()
+                .copied()
+                .filter(|file_name| !claimed.contains(*file_name))
+                .collect::<Vec<_>>();
+
+            for archive in extractor.find_archives(path, &remaining, source) {
+                claimed.extend(archive.volumes.iter().cloned());
+                archives.push((extractor.as_ref(), archive));
+            }
+        }
+
+        archives
+    }
+}
+
+impl Default for ExtractorRegistry {
+    /// Split archives come first, so that their `.zip` part is not taken as a standalone archive.
+    fn default() -> Self {
+        let mut registry = Self::new();
+        registry
+            .register(SplitZipExtractor)
+            .register(RarExtractor)
+            .register(SevenZipExtractor)
+            .register(ZipExtractor);
+        registry
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ArchiveFormat {
+    Zip,
+    Rar,
+    SevenZip,
+}
+
+/// The extension marks a file as an archive, while the signature decides its actual format.
+/// Executables are mostly plain programs, so they are only taken as self-extracting RAR archives
+/// when a RAR signature follows the stub.
+pub fn detect_format(path: &Path, file_name: &str, source: ArchiveSource) -> Option<ArchiveFormat> {
+    let path = path.join(file_name);
+    let lower = file_name.to_ascii_lowercase();
+    let format = if lower.ends_with(".zip") {
+        ArchiveFormat::Zip
+    } else if lower.ends_with(".rar") {
+        ArchiveFormat::Rar
+    } else if lower.ends_with(".7z") {
+        ArchiveFormat::SevenZip
+    } else if lower.ends_with(".exe") && has_sfx_signature(&path) {
+        return Some(ArchiveFormat::Rar);
+    } else {
+        return None;
+    };
+
+    match sniff_format(&path) {
+        Some(format) => Some(format),
+        None if source == ArchiveSource::Product => Some(format),
+        None => None,
+    }
+}
+
+fn sniff_format(path: &Path) -> Option<ArchiveFormat> {
+    let mut signature = [0u8; 6];
+    File::open(path).ok()?.read_exact(&mut signature).ok()?;
+
+    match &signature {
+        [b'P', b'K', 3, 4, ..] | [b'P', b'K', 5, 6, ..] | [b'P', b'K', 7, 8, ..] => {
+            Some(ArchiveFormat::Zip)
+        }
+        signature if signature == RAR_SIGNATURE => Some(ArchiveFormat::Rar),
+        b"7z\xbc\xaf\x27\x1c" => Some(ArchiveFormat::SevenZip),
+        _ => None,
+    }
+}
+
+fn has_sfx_signature(path: &Path) -> bool {
+    let mut head = Vec::new();
+
+    match File::open(path).and_then(|file| file.take(MAX_SFX_SIZE).read_to_end(&mut head)) {
+        Ok(_) => head
+            .windows(RAR_SIGNATURE.len())
+            .any(|window| window == RAR_SIGNATURE),
+        Err(_) => false,
+    }
+}
+
+/// Strips the archive and volume extensions, e.g. `mp3.part1.rar` and `mp3.zip.001` become `mp3`.
+pub fn get_archive_stem(file_name: &str) -> &str {
+    let lower = file_name.to_ascii_lowercase();
+    let is_digits = |text: &str| !text.is_empty() && text.bytes().all(|byte| byte.is_ascii_digit());
+    let mut end = lower.len();
+
+    if let Some(position) = lower.rfind('.') {
+        let digits = &lower[position + 1..];
+
+        if 3 <= digits.len() && is_digits(digits) {
+            end = position;
+        }
+    }
+
+    if let Some(position) = lower[..end].rfind('.') {
+        let extension = &lower[position + 1..end];
+
+        if matches!(extension, "zip" | "rar" | "7z" | "exe")
+            || (extension.starts_with(['z', 'r'])
+                && 2 < extension.len()
+                && is_digits(&extension[1..]))
+        {
+            end = position;
+        }
+    }
+
+    if let Some(position) = lower[..end].rfind(".part") {
+        if is_digits(&lower[position + 5..end]) {
+            end = position;
+        }
+    }
+
+    if end == 0 {
+        file_name
+    } else {
+        &file_name[..end]
+    }
+}
+
+/// Groups `xxx.<extension>.001`, `xxx.<extension>.002`, ... into sets starting from the first part.
+fn find_numbered_volume_sets(file_names: &[&str], extension: &str) -> Vec<ArchiveSet> {
+    let suffix = format!(".{}", extension);
+    let mut sets = BTreeMap::<&str, Vec<(u32, &str)>>::new();
+
+    for &file_name in file_names {
+        let position = match file_name.rfind('.') {
+            Some(position) => position,
+            None => continue,
+        };
+        let digits = &file_name[position + 1..];
+
+        if digits.len() < 3
+            || !digits.bytes().all(|byte| byte.is_ascii_digit())
+            || !file_name[..position]
+                .to_ascii_lowercase()
+                .ends_with(&suffix)
+        {
+            continue;
+        }
+
+        if let Ok(index) = digits.parse() {
+            sets.entry(&file_name[..position])
+                .or_default()
+                .push((index, file_name));
+        }
+    }
+
+    sets.into_values()
+        .filter_map(|mut volumes| {
+            volumes.sort();
+
+            if volumes[0].0 != 1 {
+                return None;
+            }
+
+            Some(ArchiveSet {
+                volumes: volumes
+                    .into_iter()
+                    .map(|(_, file_name)| file_name.to_owned())
+                    .collect(),
+            })
+        })
+        .collect()
+}
+
+/// Runs `attempt` without a password first, then with each known password for as long as the
+/// archive turns it down.
+fn with_passwords<T>(
+    options: &ExtractOptions,
+    mut attempt: impl FnMut(Option<&str>) -> Result<T>,
+) -> Result<T> {
+    let mut result = attempt(None);
+
+    for password in &options.passwords {
+        if !matches!(result, Err(Error::ArchivePasswordRequired { .. })) {
+            break;
+        }
+
+        result = attempt(Some(password));
+    }
+
+    result
+}
+
+fn extract_with_passwords(
+    context: &ExtractContext,
+    mut extract: impl FnMut(Option<&str>) -> Result<()>,
+) -> Result<()> {
+    with_passwords(context.options, |password| {
+        if password.is_some() {
+            context.token.check()?;
+        }
+
+        extract(password)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::write;
+
+    #[test]
+    fn executables_are_archives_only_with_a_rar_signature() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut sfx = b"MZ\x90\x00".to_vec();
+        sfx.resize(0x1000, 0);
+        sfx.extend_from_slice(RAR_SIGNATURE);
+
+        write(dir.path().join("Game.exe"), b"MZ\x90\x00").unwrap();
+        write(dir.path().join("Data.exe"), sfx).unwrap();
+
+        for source in [ArchiveSource::Product, ArchiveSource::Extracted] {
+            assert_eq!(detect_format(dir.path(), "Game.exe", source), None);
+            assert_eq!(
+                detect_format(dir.path(), "Data.exe", source),
+                Some(ArchiveFormat::Rar)
+            );
+        }
+    }
+
+    #[test]
+    fn extensions_are_trusted_only_for_product_files() {
+        let dir = tempfile::tempdir().unwrap();
+
+        write(dir.path().join("readme.zip"), b"not an archive").unwrap();
+
+        assert_eq!(
+            detect_format(dir.path(), "readme.zip", ArchiveSource::Product),
+            Some(ArchiveFormat::Zip)
+        );
+        assert_eq!(
+            detect_format(dir.path(), "readme.zip", ArchiveSource::Extracted),
+            None
+        );
+    }
+}
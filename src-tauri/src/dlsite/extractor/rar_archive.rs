@@ -0,0 +1,322 @@
+use super::{
+    detect_format, extract_with_passwords, with_passwords, ArchiveEntry, ArchiveFormat, ArchiveSet,
+    ArchiveSource, ExtractContext, ExtractOptions, Extractor,
+};
+use crate::{
+    application_error::{Error, Result},
+    dlsite::progress::DownloadPhase,
+};
+use std::{
+    collections::BTreeMap,
+    fs::{create_dir_all, rename},
+    path::{Path, PathBuf},
+};
+use unrar::{
+    error::{Code, UnrarError},
+    Archive,
+};
+
+pub struct RarExtractor;
+
+impl Extractor for RarExtractor {
+    fn find_archives(
+        &self,
+        path: &Path,
+        file_names: &[&str],
+        source: ArchiveSource,
+    ) -> Vec<ArchiveSet> {
+        let mut archives = Vec::new();
+        let mut volume_names = Vec::new();
+
+        for &file_name in file_names {
+            match detect_format(path, file_name, source) {
+                Some(ArchiveFormat::Rar) if parse_volume_name(file_name).is_some() => {
+                    volume_names.push(file_name)
+                }
+                // Continuation volumes such as `.r00` have no extension of their own to detect.
+                None if is_continuation_volume(file_name) => volume_names.push(file_name),
+                // A RAR archive under another extension can only be a single volume.
+                Some(ArchiveFormat::Rar) => archives.push(ArchiveSet {
+                    volumes: vec![file_name.to_owned()],
+                }),
+                _ => {}
+            }
+        }
+
+        archives.extend(find_rar_volume_sets(volume_names));
+        archives
+    }
+
+    fn extract(
+        &self,
+        path: &Path,
+        archive: &ArchiveSet,
+        target_path: &Path,
+        context: &ExtractContext,
+    ) -> Result<()> {
+        extract_with_passwords(context, |password| {
+            with_rar_path(path, archive, |rar_path| {
+                extract_rar(
+                    rar_path,
+                    target_path,
+                    &archive.volumes[0],
+                    password,
+                    context,
+                )
+            })
+        })
+    }
+
+    fn list_entries(
+        &self,
+        path: &Path,
+        archive: &ArchiveSet,
+        options: &ExtractOptions,
+    ) -> Result<Vec<ArchiveEntry>> {
+        // The first volume is opened under its own name, as listing must leave the files untouched.
+        with_passwords(options, |password| {
+            Ok(list_rar(&path.join(&archive.volumes[0]), password)
+                .map_err(|err| map_password_error(err, archive))?
+                .into_iter()
+                .map(|(name, (size, is_dir))| ArchiveEntry {
+                    name: name.to_string_lossy().into_owned(),
+                    size,
+                    is_dir,
+                })
+                .collect())
+        })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+enum VolumeScheme {
+    // `xxx.part1.exe`, `xxx.part2.rar`, ...
+    Part,
+    // `xxx.exe` or `xxx.rar`, followed by `xxx.r00`, `xxx.r01`, ...
+    Extension,
+}
+
+/// Groups product files into RAR volume sets, following the naming schemes the DLsite uses.
+fn find_rar_volume_sets<'a>(file_names: impl IntoIterator<Item = &'a str>) -> Vec<ArchiveSet> {
+    let mut sets = BTreeMap::<(VolumeScheme, String), Vec<(u32, &str)>>::new();
+
+    for file_name in file_names {
+        if let Some((scheme, base, index)) = parse_volume_name(file_name) {
+            sets.entry((scheme, base))
+                .or_default()
+                .push((index, file_name));
+        }
+    }
+
+    sets.into_values()
+        .filter_map(|mut volumes| {
+            volumes.sort();
+
+            // Continuation volumes without the first one cannot be extracted.
+            let first = volumes[0].1.to_ascii_lowercase();
+
+            if !first.ends_with(".exe") && !first.ends_with(".rar") {
+                return None;
+            }
+
+            Some(ArchiveSet {
+                volumes: volumes
+                    .into_iter()
+                    .map(|(_, file_name)| file_name.to_owned())
+                    .collect(),
+            })
+        })
+        .collect()
+}
+
+/// Runs `run` on the path of the first volume under a name unrar accepts, turning the errors caused
+/// by a missing or wrong password into [`Error::ArchivePasswordRequired`].
+fn with_rar_path<T>(
+    path: &Path,
+    set: &ArchiveSet,
+    run: impl FnOnce(&Path) -> Result<T>,
+) -> Result<T> {
+    let first_path = path.join(&set.volumes[0]);
+
+    // Self-extracting first volumes are renamed, since unrar looks up the next volumes from the
+    // name of the first one.
+    let rar_path = if first_path
+        .extension()
+        .map(|extension| extension.eq_ignore_ascii_case("exe"))
+        .unwrap_or(false)
+    {
+        first_path.with_extension("rar")
+    } else {
+        first_path.clone()
+    };
+
+    if rar_path != first_path {
+        rename(&first_path, &rar_path)
+            .map_err(|err| Error::ProductRarArchiveRenameError { io_error: err })?;
+    }
+
+    let result = run(&rar_path).map_err(|err| map_password_error(err, set));
+
+    // The original name is restored even on failure, so that the archive can be opened again.
+    if rar_path != first_path {
+        rename(&rar_path, &first_path)
+            .map_err(|err| Error::ProductRarArchiveRenameError { io_error: err })?;
+    }
+
+    result
+}
+
+fn extract_rar(
+    rar_path: &Path,
+    target_path: &Path,
+    file_name: &str,
+    password: Option<&str>,
+    context: &ExtractContext,
+) -> Result<()> {
+    let entries = list_rar(rar_path, password)?;
+
+    context.tracker.begin_phase(
+        DownloadPhase::Extracting,
+        entries.values().map(|(size, _)| size).sum(),
+    )?;
+
+    let mut archive = open_rar(rar_path, password)?
+        .open_for_processing()
+        .map_err(|err| Error::ProductRarArchiveExtractOpenError { extract_error: err })?;
+
+    while let Some(header) = archive
+        .read_header()
+        .map_err(|err| Error::ProductRarArchiveExtractProcessError { extract_error: err })?
+    {
+        context.token.check()?;
+
+        let entry_size = header.entry().unpacked_size as u64;
+        let is_directory = header.entry().is_directory();
+        let path = context
+            .paths
+            .resolve(
+                target_path,
+                file_name,
+                &header.entry().filename.to_string_lossy(),
+            )
+            .filter(|path| !path.as_os_str().is_empty())
+            .map(|path| target_path.join(path));
+
+        // Entries are written to the resolved paths instead of the ones stored in the archive.
+        archive = match path {
+            Some(path) if is_directory => {
+                create_dir_all(&path)
+                    .map_err(|err| Error::ProductArchiveWriteError { io_error: err })?;
+                header.skip()
+            }
+            Some(path) => {
+                if let Some(parent) = path.parent() {
+                    create_dir_all(parent)
+                        .map_err(|err| Error::ProductArchiveWriteError { io_error: err })?;
+                }
+
+                header.extract_to(&path)
+            }
+            None => header.skip(),
+        }
+        .map_err(|err| Error::ProductRarArchiveExtractProcessError { extract_error: err })?;
+        context.tracker.advance(entry_size)?;
+    }
+
+    Ok(())
+}
+
+fn open_rar<'a>(rar_path: &'a Path, password: Option<&'a str>) -> Result<Archive<'a>> {
+    let rar_path = rar_path.to_str().ok_or_else(|| Error::NonUtf8PathError {
+        path: rar_path.to_owned(),
+    })?;
+
+    Ok(match password {
+        Some(password) => Archive::with_password(rar_path, password),
+        None => Archive::new(rar_path),
+    })
+}
+
+/// Returns the unpacked size of each entry, and whether it is a directory.
+fn list_rar(rar_path: &Path, password: Option<&str>) -> Result<BTreeMap<PathBuf, (u64, bool)>> {
+    // Entries split across volumes are listed once per volume.
+    let mut entries = BTreeMap::new();
+
+    for entry in open_rar(rar_path, password)?
+        .open_for_listing()
+        .map_err(|err| Error::ProductRarArchiveExtractOpenError { extract_error: err })?
+    {
+        let entry = entry
+            .map_err(|err| Error::ProductRarArchiveExtractProcessError { extract_error: err })?;
+        let is_directory = entry.is_directory();
+
+        entries.insert(entry.filename, (entry.unpacked_size as u64, is_directory));
+    }
+
+    Ok(entries)
+}
+
+fn map_password_error(err: Error, set: &ArchiveSet) -> Error {
+    match err {
+        Error::ProductRarArchiveExtractOpenError { extract_error }
+        | Error::ProductRarArchiveExtractProcessError { extract_error }
+            if is_password_error(&extract_error) =>
+        {
+            Error::ArchivePasswordRequired {
+                file_name: set.volumes[0].clone(),
+            }
+        }
+        err => err,
+    }
+}
+
+fn is_password_error(err: &UnrarError) -> bool {
+    matches!(err.code, Code::MissingPassword | Code::BadPassword)
+}
+
+fn is_continuation_volume(file_name: &str) -> bool {
+    let lower = file_name.to_ascii_lowercase();
+
+    !lower.ends_with(".exe") && !lower.ends_with(".rar") && parse_volume_name(file_name).is_some()
+}
+
+fn parse_volume_name(file_name: &str) -> Option<(VolumeScheme, String, u32)> {
+    // Lowercasing ASCII keeps byte offsets, so they can be used to slice the original name.
+    let lower = file_name.to_ascii_lowercase();
+
+    if let Some(stem) = lower
+        .strip_suffix(".exe")
+        .or_else(|| lower.strip_suffix(".rar"))
+    {
+        if let Some(position) = stem.rfind(".part") {
+            let digits = &stem[position + 5..];
+
+            if !digits.is_empty() && digits.bytes().all(|byte| byte.is_ascii_digit()) {
+                return Some((
+                    VolumeScheme::Part,
+                    file_name[..position].to_owned(),
+                    digits.parse().ok()?,
+                ));
+            }
+        }
+
+        return Some((
+            VolumeScheme::Extension,
+            file_name[..stem.len()].to_owned(),
+            0,
+        ));
+    }
+
+    let position = lower.rfind(".r")?;
+    let digits = &lower[position + 2..];
+
+    if digits.len() < 2 || !digits.bytes().all(|byte| byte.is_ascii_digit()) {
+        return None;
+    }
+
+    Some((
+        VolumeScheme::Extension,
+        file_name[..position].to_owned(),
+        digits.parse::<u32>().ok()? + 1,
+    ))
+}
@@ -0,0 +1,375 @@
+use parking_lot::Mutex;
+use std::{
+    collections::{HashMap, HashSet},
+    path::{Path, PathBuf},
+};
+
+pub static RENAME_REPORT_FILE_NAME: &str = "renamed-entries.txt";
+
+// Most filesystems cap a name at 255 bytes, and Windows caps a whole path at 260 UTF-16 units. Some
+// of those are left for the `~2`, `~3`, ... appended to names that are taken.
+static MAX_NAME_BYTES: usize = 240;
+static MAX_PATH_UNITS: usize = 250;
+static MIN_NAME_UNITS: usize = 24;
+static MAX_EXTENSION_CHARS: usize = 16;
+static RESERVED_NAMES: [&str; 24] = [
+    "CON", "PRN", "AUX", "NUL", "CONIN$", "CONOUT$", "COM1", "COM2", "COM3", "COM4", "COM5",
+    "COM6", "COM7", "COM8", "COM9", "LPT1", "LPT2", "LPT3", "LPT4", "LPT5", "LPT6", "LPT7", "LPT8",
+    "LPT9",
+];
+
+/// An entry that was extracted under another name, or not at all.
+#[derive(Debug, Clone)]
+pub struct RenamedEntry {
+    pub archive: String,
+    pub name: String,
+    /// Where the entry was extracted to, or `None` for entries that were skipped.
+    pub path: Option<PathBuf>,
+}
+
+#[derive(Default)]
+struct ResolverState {
+    // Resolved name of each original name, keyed by the resolved parent directory.
+    names: HashMap<(PathBuf, String), String>,
+    // Lowercased resolved names, as Windows and most NAS shares ignore case.
+    claimed: HashSet<(PathBuf, String)>,
+    reported: HashSet<(PathBuf, String)>,
+    renamed: Vec<RenamedEntry>,
+    // Where the contents of each target directory end up once extracted.
+    destinations: HashMap<PathBuf, PathBuf>,
+}
+
+/// Maps archive entry names onto paths that can be created on Windows and NAS shares alike. The same
+/// name always resolves to the same path, so that retried extractions produce the same output.
+#[derive(Default)]
+pub struct SafePathResolver {
+    state: Mutex<ResolverState>,
+}
+
+impl SafePathResolver {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Sets where the contents of `target_path` are moved to, which the path length is measured
+    /// against.
+    pub fn set_destination(&self, target_path: &Path, destination: &Path) {
+        self.state
+            .lock()
+            .destinations
+            .insert(target_path.to_owned(), destination.to_owned());
+    }
+
+    /// Returns the path of the entry relative to `target_path`, where an empty path stands for
+    /// `target_path` itself. Entries that are absolute, climb out of it or do not fit into the path
+    /// length give `None`.
+    pub fn resolve(&self, target_path: &Path, archive: &str, name: &str) -> Option<PathBuf> {
+        let mut state = self.state.lock();
+        let components = match split_entry_name(name) {
+            Some(components) => components,
+            None => {
+                state.report(target_path, archive, name, None);
+                return None;
+            }
+        };
+        let mut parent_path = target_path.to_owned();
+        let mut path = PathBuf::new();
+        let mut path_units = state
+            .get_destination_path(target_path)
+            .to_string_lossy()
+            .encode_utf16()
+            .count();
+
+        for component in &components {
+            let key = (parent_path.clone(), component.to_string());
+            let resolved = match state.names.get(&key) {
+                Some(resolved) => resolved.clone(),
+                None => {
+                    let max_units = MAX_PATH_UNITS.saturating_sub(path_units + 1);
+
+                    if max_units < MIN_NAME_UNITS {
+                        state.report(target_path, archive, name, None);
+                        return None;
+                    }
+
+                    let resolved = state.claim(&parent_path, sanitize_name(component, max_units));
+                    state.names.insert(key, resolved.clone());
+                    resolved
+                }
+            };
+
+            path_units += 1 + resolved.encode_utf16().count();
+            parent_path.push(&resolved);
+            path.push(&resolved);
+        }
+
+        if path != components.iter().collect::<PathBuf>() {
+            state.report(target_path, archive, name, Some(target_path.join(&path)));
+        }
+
+        Some(path)
+    }
+
+    /// Drops what was resolved under `target_path`, once its contents have been moved elsewhere.
+    pub fn forget(&self, target_path: &Path) {
+        let mut state = self.state.lock();
+        state
+            .names
+            .retain(|(path, _), _| !path.starts_with(target_path));
+        state
+            .claimed
+            .retain(|(path, _)| !path.starts_with(target_path));
+        state
+            .reported
+            .retain(|(path, _)| !path.starts_with(target_path));
+        state
+            .destinations
+            .retain(|path, _| !path.starts_with(target_path));
+    }
+
+    /// Updates the paths of the renamed entries under `from`, once they have been moved to `to`.
+    pub fn relocate(&self, from: &Path, to: &Path) {
+        for entry in &mut self.state.lock().renamed {
+            if let Some(path) = &mut entry.path {
+                if let Ok(relative_path) = path.strip_prefix(from) {
+                    *path = to.join(relative_path);
+                }
+            }
+        }
+    }
+
+    pub fn renamed_entries(&self) -> Vec<RenamedEntry> {
+        self.state.lock().renamed.clone()
+    }
+}
+
+impl ResolverState {
+    /// Follows the destinations of nested targets up to where the path finally ends up.
+    fn get_destination_path(&self, path: &Path) -> PathBuf {
+        let mut path = path.to_owned();
+
+        while let Some((target_path, destination)) = self
+            .destinations
+            .iter()
+            .filter(|(target_path, destination)| {
+                path.starts_with(target_path) && !destination.starts_with(target_path)
+            })
+            .max_by_key(|(target_path, _)| target_path.components().count())
+        {
+            path = destination.join(path.strip_prefix(target_path).unwrap());
+        }
+
+        path
+    }
+
+    /// Appends `~2`, `~3`, ... to names that another entry in the same directory already took.
+    fn claim(&mut self, parent_path: &Path, name: String) -> String {
+        let (stem, extension) = split_extension(&name);
+        let name = (1..)
+            .map(|index| match index {
+                1 => name.clone(),
+                index => format!("{}~{}{}", stem, index, extension),
+            })
+            .find(|name| {
+                !self
+                    .claimed
+                    .contains(&(parent_path.to_owned(), name.to_lowercase()))
+            })
+            .unwrap();
+
+        self.claimed
+            .insert((parent_path.to_owned(), name.to_lowercase()));
+        name
+    }
+
+    fn report(&mut self, target_path: &Path, archive: &str, name: &str, path: Option<PathBuf>) {
+        if self
+            .reported
+            .insert((target_path.to_owned(), name.to_owned()))
+        {
+            self.renamed.push(RenamedEntry {
+                archive: archive.to_owned(),
+                name: name.to_owned(),
+                path,
+            });
+        }
+    }
+}
+
+fn split_entry_name(name: &str) -> Option<Vec<&str>> {
+    let bytes = name.as_bytes();
+
+    // Leading separators and drive letters make the entry absolute.
+    if name.starts_with(['/', '\\'])
+        || (2 <= bytes.len() && bytes[0].is_ascii_alphabetic() && bytes[1] == b':')
+    {
+        return None;
+    }
+
+    let mut components = Vec::new();
+
+    for component in name.split(['/', '\\']) {
+        match component {
+            "" | "." => {}
+            ".." => return None,
+            component => components.push(component),
+        }
+    }
+
+    Some(components)
+}
+
+fn sanitize_name(name: &str, max_units: usize) -> String {
+    let mut sanitized = name
+        .chars()
+        .map(|c| match c {
+            '<' | '>' | ':' | '"' | '|' | '?' | '*' => '_',
+            c if c.is_control() => '_',
+            c => c,
+        })
+        .collect::<String>();
+
+    // Windows drops trailing dots and spaces, which would merge the entry with another one.
+    sanitized.truncate(sanitized.trim_end_matches(['.', ' ']).len());
+
+    if sanitized.is_empty() {
+        sanitized.push('_');
+    }
+
+    let device_name = sanitized.split('.').next().unwrap_or_default().trim_end();
+
+    if RESERVED_NAMES
+        .iter()
+        .any(|reserved| reserved.eq_ignore_ascii_case(device_name))
+    {
+        sanitized.insert(device_name.len(), '_');
+    }
+
+    if MAX_NAME_BYTES < sanitized.len() || max_units < sanitized.encode_utf16().count() {
+        sanitized = shorten_name(&sanitized, name, max_units);
+    }
+
+    sanitized
+}
+
+/// Cuts the stem short and appends a hash of the original name, keeping the extension.
+fn shorten_name(name: &str, original: &str, max_units: usize) -> String {
+    let (stem, extension) = split_extension(name);
+    let suffix = format!("~{:08x}{}", hash_name(original), extension);
+    let mut bytes = suffix.len();
+    let mut units = suffix.encode_utf16().count();
+    let mut shortened = String::new();
+
+    for c in stem.chars() {
+        bytes += c.len_utf8();
+        units += c.len_utf16();
+
+        if MAX_NAME_BYTES < bytes || max_units < units {
+            break;
+        }
+
+        shortened.push(c);
+    }
+
+    shortened.truncate(shortened.trim_end_matches(['.', ' ']).len());
+    shortened + &suffix
+}
+
+fn split_extension(name: &str) -> (&str, &str) {
+    match name.rfind('.') {
+        Some(position)
+            if 0 < position && name[position..].chars().count() <= MAX_EXTENSION_CHARS =>
+        {
+            name.split_at(position)
+        }
+        _ => (name, ""),
+    }
+}
+
+// FNV-1a, as the hash must not change between runs or builds.
+fn hash_name(name: &str) -> u32 {
+    name.bytes().fold(0x811c9dc5, |hash, byte| {
+        (hash ^ byte as u32).wrapping_mul(0x01000193)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn count_units(path: &Path) -> usize {
+        path.to_string_lossy().encode_utf16().count()
+    }
+
+    #[test]
+    fn invalid_characters_and_trailing_dots_are_replaced() {
+        assert_eq!(sanitize_name("a<b>:c?. ", 100), "a_b__c_");
+        assert_eq!(sanitize_name("tab\there", 100), "tab_here");
+        assert_eq!(sanitize_name("...", 100), "_");
+        assert_eq!(sanitize_name("日本語.txt", 100), "日本語.txt");
+    }
+
+    #[test]
+    fn reserved_names_are_suffixed() {
+        assert_eq!(sanitize_name("CON", 100), "CON_");
+        assert_eq!(sanitize_name("con.txt", 100), "con_.txt");
+        assert_eq!(sanitize_name("lpt1 .tar.gz", 100), "lpt1_ .tar.gz");
+        assert_eq!(sanitize_name("CONSOLE.txt", 100), "CONSOLE.txt");
+    }
+
+    #[test]
+    fn long_names_are_shortened_with_a_hash() {
+        let name = format!("{}.txt", "a".repeat(300));
+        let shortened = sanitize_name(&name, 50);
+
+        assert!(shortened.encode_utf16().count() <= 50);
+        assert!(shortened.ends_with(".txt"));
+        assert_eq!(shortened, shorten_name(&name, &name, 50));
+        assert_ne!(
+            shortened,
+            shorten_name(&name, &format!("{}.txt", "b".repeat(300)), 50)
+        );
+
+        let name = format!("{}.png", "あ".repeat(200));
+        let shortened = sanitize_name(&name, 250);
+
+        assert!(shortened.len() <= MAX_NAME_BYTES);
+        assert!(shortened.ends_with(".png"));
+    }
+
+    #[test]
+    fn paths_fit_into_the_budget_of_the_destination() {
+        let resolver = SafePathResolver::new();
+        let target_path = Path::new("/tmp");
+        let destination = PathBuf::from(format!("/{}", "d".repeat(100)));
+        let name = format!("{}/{}.txt", "x".repeat(100), "y".repeat(100));
+
+        resolver.set_destination(target_path, &destination);
+
+        let path = resolver.resolve(target_path, "a.zip", &name).unwrap();
+
+        assert!(count_units(&destination.join(&path)) <= MAX_PATH_UNITS);
+        assert!(path.starts_with("x".repeat(100)));
+
+        let deeper_name = format!("{}/{}", "x".repeat(100), name);
+
+        assert_eq!(resolver.resolve(target_path, "a.zip", &deeper_name), None);
+        assert_eq!(resolver.renamed_entries()[1].path, None);
+    }
+
+    #[test]
+    fn reported_paths_follow_the_moved_contents() {
+        let resolver = SafePathResolver::new();
+        let target_path = Path::new("/product/__tmp__/0");
+
+        resolver
+            .resolve(target_path, "a.zip", "dir/a?.txt")
+            .unwrap();
+        resolver.relocate(&target_path.join("dir"), Path::new("/product"));
+
+        assert_eq!(
+            resolver.renamed_entries()[0].path,
+            Some(PathBuf::from("/product/a_.txt"))
+        );
+    }
+}
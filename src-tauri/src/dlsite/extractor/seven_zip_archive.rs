@@ -0,0 +1,176 @@
+use super::{
+    detect_format, extract_with_passwords, find_numbered_volume_sets, volume_reader::VolumeReader,
+    with_passwords, ArchiveEntry, ArchiveFormat, ArchiveSet, ArchiveSource, ExtractContext,
+    ExtractOptions, Extractor,
+};
+use crate::{
+    application_error::{Error, Result},
+    dlsite::progress::{DownloadPhase, ProgressReader},
+};
+use sevenz_rust::{Password, SevenZArchiveEntry, SevenZReader};
+use std::{
+    fs::{create_dir_all, File},
+    io::{copy, sink, BufReader, BufWriter, Read, Result as IOResult, Write},
+    path::{Path, PathBuf},
+};
+
+pub struct SevenZipExtractor;
+
+impl Extractor for SevenZipExtractor {
+    fn find_archives(
+        &self,
+        path: &Path,
+        file_names: &[&str],
+        source: ArchiveSource,
+    ) -> Vec<ArchiveSet> {
+        let mut archives = find_numbered_volume_sets(file_names, "7z");
+
+        archives.extend(
+            file_names
+                .iter()
+                .filter(|file_name| {
+                    detect_format(path, file_name, source) == Some(ArchiveFormat::SevenZip)
+                })
+                .map(|file_name| ArchiveSet {
+                    volumes: vec![file_name.to_string()],
+                }),
+        );
+        archives
+    }
+
+    fn extract(
+        &self,
+        path: &Path,
+        archive: &ArchiveSet,
+        target_path: &Path,
+        context: &ExtractContext,
+    ) -> Result<()> {
+        extract_with_passwords(context, |password| {
+            extract_seven_zip(path, archive, target_path, password, context)
+        })
+    }
+
+    // Only archives with encrypted headers need a password to be listed.
+    fn list_entries(
+        &self,
+        path: &Path,
+        archive: &ArchiveSet,
+        options: &ExtractOptions,
+    ) -> Result<Vec<ArchiveEntry>> {
+        with_passwords(options, |password| {
+            let reader = open_volumes(path, archive)?;
+            let archive_size = reader.size();
+            let seven_zip = SevenZReader::new(
+                BufReader::new(reader),
+                archive_size,
+                password.map_or_else(Password::empty, Password::from),
+            )
+            .map_err(|err| map_error(err, archive))?;
+
+            Ok(seven_zip
+                .archive()
+                .files
+                .iter()
+                .map(|entry| ArchiveEntry {
+                    name: entry.name().to_owned(),
+                    size: entry.size(),
+                    is_dir: entry.is_directory(),
+                })
+                .collect())
+        })
+    }
+}
+
+fn extract_seven_zip(
+    path: &Path,
+    archive: &ArchiveSet,
+    target_path: &Path,
+    password: Option<&str>,
+    context: &ExtractContext,
+) -> Result<()> {
+    let reader = open_volumes(path, archive)?;
+    let archive_size = reader.size();
+
+    context
+        .tracker
+        .begin_phase(DownloadPhase::Extracting, archive_size)?;
+
+    let map_error = |err| map_error(err, archive);
+    let mut seven_zip = SevenZReader::new(
+        ProgressReader::new(BufReader::new(reader), context.token, context.tracker),
+        archive_size,
+        password.map_or_else(Password::empty, Password::from),
+    )
+    .map_err(map_error)?;
+    let mut write_error = None;
+
+    let result = seven_zip.for_each_entries(|entry, reader| {
+        let path = context
+            .paths
+            .resolve(target_path, &archive.volumes[0], entry.name())
+            .filter(|path| !path.as_os_str().is_empty())
+            .map(|path| target_path.join(path));
+
+        match write_entry(entry, reader, path) {
+            Ok(()) => Ok(true),
+            Err(err) => {
+                // Aborts the whole archive, since stopping only ends the current block.
+                write_error = Some(err);
+                Err(sevenz_rust::Error::other("cannot write extracted file"))
+            }
+        }
+    });
+
+    if let Some(err) = write_error {
+        return Err(Error::ProductArchiveWriteError { io_error: err });
+    }
+
+    result.map_err(map_error)
+}
+
+fn open_volumes(path: &Path, archive: &ArchiveSet) -> Result<VolumeReader> {
+    VolumeReader::open(
+        &archive
+            .volumes
+            .iter()
+            .map(|volume| path.join(volume))
+            .collect::<Vec<_>>(),
+    )
+    .map_err(|err| Error::ProductArchiveOpenError { io_error: err })
+}
+
+fn map_error(err: sevenz_rust::Error, archive: &ArchiveSet) -> Error {
+    match err {
+        sevenz_rust::Error::PasswordRequired | sevenz_rust::Error::MaybeBadPassword(_) => {
+            Error::ArchivePasswordRequired {
+                file_name: archive.volumes[0].clone(),
+            }
+        }
+        _ => Error::ProductSevenZipArchiveExtractError { extract_error: err },
+    }
+}
+
+fn write_entry(
+    entry: &SevenZArchiveEntry,
+    reader: &mut dyn Read,
+    path: Option<PathBuf>,
+) -> IOResult<()> {
+    // Entries escaping the target directory are skipped, but their data still has to be read
+    // through to reach the next entry in a solid block.
+    let path = match path {
+        Some(path) => path,
+        None => return copy(reader, &mut sink()).map(|_| ()),
+    };
+
+    if entry.is_directory() {
+        return create_dir_all(&path);
+    }
+
+    if let Some(parent) = path.parent() {
+        create_dir_all(parent)?;
+    }
+
+    let mut writer = BufWriter::new(File::create(&path)?);
+    copy(reader, &mut writer)?;
+    writer.flush()
+}
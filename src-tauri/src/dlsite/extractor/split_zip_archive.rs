@@ -0,0 +1,439 @@
+use super::{
+    extract_with_passwords, find_numbered_volume_sets,
+    volume_reader::VolumeReader,
+    zip_archive::{extract_zip, list_zip},
+    ArchiveEntry, ArchiveSet, ArchiveSource, ExtractContext, ExtractOptions, Extractor,
+};
+use crate::{
+    application_error::{Error, Result},
+    dlsite::progress::{DownloadPhase, ProgressReader},
+};
+use std::{
+    collections::BTreeMap,
+    io::{BufReader, Read, Seek, SeekFrom},
+    path::Path,
+};
+use zip::result::ZipError;
+
+static EOCD_SIGNATURE: u32 = 0x06054b50;
+static EOCD_SIZE: usize = 22;
+static ZIP64_EOCD_SIGNATURE: u32 = 0x06064b50;
+static ZIP64_EOCD_SIZE: usize = 56;
+static ZIP64_EOCD_LOCATOR_SIGNATURE: u32 = 0x07064b50;
+static ZIP64_EOCD_LOCATOR_SIZE: usize = 20;
+static CENTRAL_HEADER_SIGNATURE: u32 = 0x02014b50;
+static CENTRAL_HEADER_SIZE: usize = 46;
+static ZIP64_EXTRA_FIELD_ID: u16 = 0x0001;
+static MAX_COMMENT_SIZE: usize = 0xffff;
+
+/// Handles both ways a ZIP archive gets split:
+/// - `xxx.zip.001`, `xxx.zip.002`, ... are plain byte chunks of a single archive.
+/// - `xxx.z01`, `xxx.z02`, ..., `xxx.zip` are spanned volumes whose offsets are per volume.
+pub struct SplitZipExtractor;
+
+impl Extractor for SplitZipExtractor {
+    fn find_archives(
+        &self,
+        _path: &Path,
+        file_names: &[&str],
+        _source: ArchiveSource,
+    ) -> Vec<ArchiveSet> {
+        let mut archives = find_numbered_volume_sets(file_names, "zip");
+        archives.extend(find_spanned_volume_sets(file_names));
+        archives
+    }
+
+    fn extract(
+        &self,
+        path: &Path,
+        archive: &ArchiveSet,
+        target_path: &Path,
+        context: &ExtractContext,
+    ) -> Result<()> {
+        extract_with_passwords(context, |password| {
+            let reader = open_volumes(path, archive)?;
+
+            context
+                .tracker
+                .begin_phase(DownloadPhase::Extracting, reader.size())?;
+            extract_zip(
+                ProgressReader::new(BufReader::new(reader), context.token, context.tracker),
+                target_path,
+                &archive.volumes[0],
+                password,
+                context,
+            )
+        })
+    }
+
+    fn list_entries(
+        &self,
+        path: &Path,
+        archive: &ArchiveSet,
+        options: &ExtractOptions,
+    ) -> Result<Vec<ArchiveEntry>> {
+        list_zip(
+            BufReader::new(open_volumes(path, archive)?),
+            options.zip_filename_encoding,
+        )
+    }
+}
+
+fn open_volumes(path: &Path, archive: &ArchiveSet) -> Result<VolumeReader> {
+    let mut reader = VolumeReader::open(
+        &archive
+            .volumes
+            .iter()
+            .map(|volume| path.join(volume))
+            .collect::<Vec<_>>(),
+    )
+    .map_err(|err| Error::ProductArchiveOpenError { io_error: err })?;
+
+    if archive.volumes[0].to_ascii_lowercase().ends_with(".z01") {
+        rebuild_spanned_archive(&mut reader)?;
+    }
+
+    Ok(reader)
+}
+
+/// Groups `xxx.z01`, `xxx.z02`, ... with the `xxx.zip` that closes the set.
+fn find_spanned_volume_sets(file_names: &[&str]) -> Vec<ArchiveSet> {
+    let mut sets = BTreeMap::<&str, Vec<(u32, &str)>>::new();
+
+    for &file_name in file_names {
+        let lower = file_name.to_ascii_lowercase();
+        let position = match lower.rfind(".z") {
+            Some(position) => position,
+            None => continue,
+        };
+        let digits = &lower[position + 2..];
+
+        if digits.len() < 2 || !digits.bytes().all(|byte| byte.is_ascii_digit()) {
+            continue;
+        }
+
+        if let Ok(index) = digits.parse() {
+            sets.entry(&file_name[..position])
+                .or_default()
+                .push((index, file_name));
+        }
+    }
+
+    sets.into_iter()
+        .filter_map(|(base, mut volumes)| {
+            volumes.sort();
+
+            if volumes[0].0 != 1 {
+                return None;
+            }
+
+            let last = file_names.iter().find(|file_name| {
+                file_name.len() == base.len() + 4
+                    && file_name.starts_with(base)
+                    && file_name[base.len()..].eq_ignore_ascii_case(".zip")
+            })?;
+
+            Some(ArchiveSet {
+                volumes: volumes
+                    .into_iter()
+                    .map(|(_, file_name)| file_name)
+                    .chain(std::iter::once(*last))
+                    .map(|file_name| file_name.to_owned())
+                    .collect(),
+            })
+        })
+        .collect()
+}
+
+/// Cuts the central directory off the concatenated volumes and appends one whose offsets point
+/// into the concatenation, so that it reads as a regular single-volume archive.
+fn rebuild_spanned_archive(reader: &mut VolumeReader) -> Result<()> {
+    let size = reader.size();
+    let volume_offsets = reader.volume_offsets().to_vec();
+    let tail_size = size.min((EOCD_SIZE + MAX_COMMENT_SIZE) as u64);
+    let tail = read_at(reader, size - tail_size, tail_size as usize)?;
+
+    if tail.len() < EOCD_SIZE {
+        return Err(invalid_archive("cannot find the end of central directory"));
+    }
+
+    let eocd_position = (0..=tail.len() - EOCD_SIZE)
+        .rev()
+        .find(|&position| read_u32(&tail, position) == EOCD_SIGNATURE)
+        .ok_or_else(|| invalid_archive("cannot find the end of central directory"))?;
+    let eocd = &tail[eocd_position..];
+    let mut entry_count = read_u16(eocd, 10) as u64;
+    let mut directory_size = read_u32(eocd, 12) as u64;
+    let mut directory_offset = read_u32(eocd, 16) as u64;
+    let mut directory_disk = read_u16(eocd, 6) as u32;
+
+    if entry_count == 0xffff || directory_size == 0xffffffff || directory_offset == 0xffffffff {
+        let locator_position = eocd_position
+            .checked_sub(ZIP64_EOCD_LOCATOR_SIZE)
+            .filter(|&position| read_u32(&tail, position) == ZIP64_EOCD_LOCATOR_SIGNATURE)
+            .ok_or_else(|| invalid_archive("cannot find the zip64 end of central directory"))?;
+        let locator = &tail[locator_position..];
+        let record_offset =
+            get_volume_offset(&volume_offsets, read_u32(locator, 4))? + read_u64(locator, 8);
+        let record = read_at(reader, record_offset, ZIP64_EOCD_SIZE)?;
+
+        if read_u32(&record, 0) != ZIP64_EOCD_SIGNATURE {
+            return Err(invalid_archive("invalid zip64 end of central directory"));
+        }
+
+        directory_disk = read_u32(&record, 20);
+        entry_count = read_u64(&record, 32);
+        directory_size = read_u64(&record, 40);
+        directory_offset = read_u64(&record, 48);
+    }
+
+    let directory_start = get_volume_offset(&volume_offsets, directory_disk)? + directory_offset;
+    let directory = read_at(reader, directory_start, directory_size as usize)?;
+    let mut rebuilt = rebuild_central_directory(&directory, &volume_offsets)?;
+    let rebuilt_size = rebuilt.len() as u64;
+    let needs_zip64 =
+        0xffff <= entry_count || 0xffffffff <= rebuilt_size || 0xffffffff <= directory_start;
+
+    if needs_zip64 {
+        let record_offset = directory_start + rebuilt_size;
+
+        write_u32(&mut rebuilt, ZIP64_EOCD_SIGNATURE);
+        write_u64(&mut rebuilt, (ZIP64_EOCD_SIZE - 12) as u64);
+        write_u16(&mut rebuilt, 45);
+        write_u16(&mut rebuilt, 45);
+        write_u32(&mut rebuilt, 0);
+        write_u32(&mut rebuilt, 0);
+        write_u64(&mut rebuilt, entry_count);
+        write_u64(&mut rebuilt, entry_count);
+        write_u64(&mut rebuilt, rebuilt_size);
+        write_u64(&mut rebuilt, directory_start);
+
+        write_u32(&mut rebuilt, ZIP64_EOCD_LOCATOR_SIGNATURE);
+        write_u32(&mut rebuilt, 0);
+        write_u64(&mut rebuilt, record_offset);
+        write_u32(&mut rebuilt, 1);
+    }
+
+    write_u32(&mut rebuilt, EOCD_SIGNATURE);
+    write_u16(&mut rebuilt, 0);
+    write_u16(&mut rebuilt, 0);
+    write_u16(&mut rebuilt, entry_count.min(0xffff) as u16);
+    write_u16(&mut rebuilt, entry_count.min(0xffff) as u16);
+    write_u32(&mut rebuilt, rebuilt_size.min(0xffffffff) as u32);
+    write_u32(&mut rebuilt, directory_start.min(0xffffffff) as u32);
+    write_u16(&mut rebuilt, 0);
+
+    reader.truncate(directory_start);
+    reader.append(rebuilt);
+    Ok(())
+}
+
+fn rebuild_central_directory(directory: &[u8], volume_offsets: &[u64]) -> Result<Vec<u8>> {
+    let mut rebuilt = Vec::with_capacity(directory.len());
+    let mut position = 0;
+
+    while position + CENTRAL_HEADER_SIZE <= directory.len() {
+        let header = &directory[position..position + CENTRAL_HEADER_SIZE];
+
+        if read_u32(header, 0) != CENTRAL_HEADER_SIGNATURE {
+            return Err(invalid_archive("invalid central directory header"));
+        }
+
+        let name_size = read_u16(header, 28) as usize;
+        let extra_size = read_u16(header, 30) as usize;
+        let comment_size = read_u16(header, 32) as usize;
+        let name_start = position + CENTRAL_HEADER_SIZE;
+        let extra_start = name_start + name_size;
+        let comment_start = extra_start + extra_size;
+        let end = comment_start + comment_size;
+
+        if directory.len() < end {
+            return Err(invalid_archive("truncated central directory header"));
+        }
+
+        let mut disk = read_u16(header, 34) as u32;
+        let mut offset = read_u32(header, 42) as u64;
+        let mut zip64_field = Vec::new();
+        let mut other_fields = Vec::new();
+        let mut extra_position = extra_start;
+
+        while extra_position + 4 <= comment_start {
+            let field_id = read_u16(directory, extra_position);
+            let field_end = (extra_position + 4 + read_u16(directory, extra_position + 2) as usize)
+                .min(comment_start);
+            let field = &directory[extra_position + 4..field_end];
+
+            if field_id == ZIP64_EXTRA_FIELD_ID {
+                // Only the saturated fields are present, always in this order.
+                let mut field_position = 0;
+
+                for saturated in [
+                    read_u32(header, 24) == 0xffffffff,
+                    read_u32(header, 20) == 0xffffffff,
+                ] {
+                    if saturated && field_position + 8 <= field.len() {
+                        zip64_field.extend_from_slice(&field[field_position..field_position + 8]);
+                        field_position += 8;
+                    }
+                }
+
+                if read_u32(header, 42) == 0xffffffff && field_position + 8 <= field.len() {
+                    offset = read_u64(field, field_position);
+                    field_position += 8;
+                }
+
+                if read_u16(header, 34) == 0xffff && field_position + 4 <= field.len() {
+                    disk = read_u32(field, field_position);
+                }
+            } else {
+                other_fields.extend_from_slice(&directory[extra_position..field_end]);
+            }
+
+            extra_position = field_end;
+        }
+
+        let offset = get_volume_offset(volume_offsets, disk)? + offset;
+        let mut header = header.to_vec();
+
+        if offset < 0xffffffff {
+            header[42..46].copy_from_slice(&(offset as u32).to_le_bytes());
+        } else {
+            header[42..46].copy_from_slice(&0xffffffffu32.to_le_bytes());
+            zip64_field.extend_from_slice(&offset.to_le_bytes());
+        }
+
+        let mut extra = Vec::with_capacity(4 + zip64_field.len() + other_fields.len());
+
+        if !zip64_field.is_empty() {
+            write_u16(&mut extra, ZIP64_EXTRA_FIELD_ID);
+            write_u16(&mut extra, zip64_field.len() as u16);
+            extra.extend_from_slice(&zip64_field);
+        }
+
+        extra.extend_from_slice(&other_fields);
+        header[30..32].copy_from_slice(&(extra.len() as u16).to_le_bytes());
+        header[34..36].copy_from_slice(&0u16.to_le_bytes());
+
+        rebuilt.extend_from_slice(&header);
+        rebuilt.extend_from_slice(&directory[name_start..extra_start]);
+        rebuilt.extend_from_slice(&extra);
+        rebuilt.extend_from_slice(&directory[comment_start..end]);
+        position = end;
+    }
+
+    Ok(rebuilt)
+}
+
+fn get_volume_offset(volume_offsets: &[u64], disk: u32) -> Result<u64> {
+    volume_offsets
+        .get(disk as usize)
+        .copied()
+        .ok_or_else(|| invalid_archive("missing volume"))
+}
+
+fn read_at(reader: &mut VolumeReader, offset: u64, size: usize) -> Result<Vec<u8>> {
+    let mut buf = vec![0; size];
+    reader
+        .seek(SeekFrom::Start(offset))
+        .and_then(|_| reader.read_exact(&mut buf))
+        .map_err(|err| Error::ProductArchiveOpenError { io_error: err })?;
+    Ok(buf)
+}
+
+fn invalid_archive(reason: &'static str) -> Error {
+    Error::ProductArchiveExtractError {
+        extract_error: ZipError::InvalidArchive(reason),
+    }
+}
+
+fn read_u16(data: &[u8], position: usize) -> u16 {
+    u16::from_le_bytes([data[position], data[position + 1]])
+}
+
+fn read_u32(data: &[u8], position: usize) -> u32 {
+    let mut bytes = [0; 4];
+    bytes.copy_from_slice(&data[position..position + 4]);
+    u32::from_le_bytes(bytes)
+}
+
+fn read_u64(data: &[u8], position: usize) -> u64 {
+    let mut bytes = [0; 8];
+    bytes.copy_from_slice(&data[position..position + 8]);
+    u64::from_le_bytes(bytes)
+}
+
+fn write_u16(data: &mut Vec<u8>, value: u16) {
+    data.extend_from_slice(&value.to_le_bytes());
+}
+
+fn write_u32(data: &mut Vec<u8>, value: u32) {
+    data.extend_from_slice(&value.to_le_bytes());
+}
+
+fn write_u64(data: &mut Vec<u8>, value: u64) {
+    data.extend_from_slice(&value.to_le_bytes());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dlsite::{extractor::SafePathResolver, progress::ProgressTracker, DownloadToken};
+    use std::{fs::read, path::PathBuf};
+
+    // Made with `zip -0 -s 64k`, so that the last entry starts in the second volume.
+    fn get_fixture_path() -> PathBuf {
+        Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("tests")
+            .join("fixtures")
+            .join("split-zip")
+    }
+
+    #[test]
+    fn spanned_volumes_are_extracted_as_one_archive() {
+        let path = get_fixture_path();
+        let archives = SplitZipExtractor.find_archives(
+            &path,
+            &["sample.zip", "sample.z01"],
+            ArchiveSource::Product,
+        );
+
+        assert_eq!(archives.len(), 1);
+        assert_eq!(archives[0].volumes, ["sample.z01", "sample.zip"]);
+
+        let dir = tempfile::tempdir().unwrap();
+        let options = ExtractOptions::default();
+        let token = DownloadToken::new();
+        let tracker = ProgressTracker::new(0, 1, |_| Ok(()));
+        let paths = SafePathResolver::new();
+        let context = ExtractContext {
+            options: &options,
+            token: &token,
+            tracker: &tracker,
+            paths: &paths,
+        };
+
+        assert_eq!(
+            SplitZipExtractor
+                .list_entries(&path, &archives[0], &options)
+                .unwrap()
+                .len(),
+            3
+        );
+
+        SplitZipExtractor
+            .extract(&path, &archives[0], dir.path(), &context)
+            .unwrap();
+
+        let sample_path = dir.path().join("sample");
+        let data = (0..100000u32)
+            .map(|index| (index * 7 % 251) as u8)
+            .collect::<Vec<_>>();
+
+        assert_eq!(
+            read(sample_path.join("readme.txt")).unwrap(),
+            b"first volume\n"
+        );
+        assert_eq!(read(sample_path.join("data.bin")).unwrap(), data);
+        assert_eq!(read(sample_path.join("end.txt")).unwrap(), b"last volume\n");
+    }
+}
@@ -0,0 +1,116 @@
+use std::{
+    fs::File,
+    io::{Error as IOError, ErrorKind, Read, Result as IOResult, Seek, SeekFrom},
+    path::PathBuf,
+};
+
+enum Segment {
+    File(File),
+    Memory(Vec<u8>),
+}
+
+/// Reads a list of volumes as if they were a single file.
+pub struct VolumeReader {
+    // Start offset, size and source of each segment.
+    segments: Vec<(u64, u64, Segment)>,
+    volume_offsets: Vec<u64>,
+    position: u64,
+}
+
+impl VolumeReader {
+    pub fn open(paths: &[PathBuf]) -> IOResult<Self> {
+        let mut reader = Self {
+            segments: Vec::with_capacity(paths.len()),
+            volume_offsets: Vec::with_capacity(paths.len()),
+            position: 0,
+        };
+
+        for path in paths {
+            let file = File::open(path)?;
+            let size = file.metadata()?.len();
+            let offset = reader.size();
+
+            reader.volume_offsets.push(offset);
+            reader.segments.push((offset, size, Segment::File(file)));
+        }
+
+        Ok(reader)
+    }
+
+    pub fn size(&self) -> u64 {
+        self.segments
+            .last()
+            .map(|(offset, size, _)| offset + size)
+            .unwrap_or(0)
+    }
+
+    /// Start offset of each volume within the whole.
+    pub fn volume_offsets(&self) -> &[u64] {
+        &self.volume_offsets
+    }
+
+    /// Drops everything past `size`.
+    pub fn truncate(&mut self, size: u64) {
+        self.segments.retain(|(offset, _, _)| *offset < size);
+
+        if let Some((offset, segment_size, _)) = self.segments.last_mut() {
+            *segment_size = (*segment_size).min(size - *offset);
+        }
+    }
+
+    pub fn append(&mut self, data: Vec<u8>) {
+        let offset = self.size();
+        self.segments
+            .push((offset, data.len() as u64, Segment::Memory(data)));
+    }
+}
+
+impl Read for VolumeReader {
+    fn read(&mut self, buf: &mut [u8]) -> IOResult<usize> {
+        let position = self.position;
+        let (offset, size, segment) = match self
+            .segments
+            .iter_mut()
+            .find(|(offset, size, _)| position < offset + size)
+        {
+            Some(segment) => segment,
+            None => return Ok(0),
+        };
+        let segment_position = position - *offset;
+        let len = (*size - segment_position).min(buf.len() as u64) as usize;
+        let read = match segment {
+            Segment::File(file) => {
+                file.seek(SeekFrom::Start(segment_position))?;
+                file.read(&mut buf[..len])?
+            }
+            Segment::Memory(data) => {
+                let start = segment_position as usize;
+                buf[..len].copy_from_slice(&data[start..start + len]);
+                len
+            }
+        };
+
+        self.position += read as u64;
+        Ok(read)
+    }
+}
+
+impl Seek for VolumeReader {
+    fn seek(&mut self, pos: SeekFrom) -> IOResult<u64> {
+        let position = match pos {
+            SeekFrom::Start(offset) => offset as i128,
+            SeekFrom::End(offset) => self.size() as i128 + offset as i128,
+            SeekFrom::Current(offset) => self.position as i128 + offset as i128,
+        };
+
+        if position < 0 {
+            return Err(IOError::new(
+                ErrorKind::InvalidInput,
+                "invalid seek to a negative position",
+            ));
+        }
+
+        self.position = position as u64;
+        Ok(self.position)
+    }
+}
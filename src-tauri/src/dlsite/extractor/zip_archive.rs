@@ -0,0 +1,211 @@
+use super::{
+    detect_format, extract_with_passwords, ArchiveEntry, ArchiveFormat, ArchiveSet, ArchiveSource,
+    ExtractContext, ExtractOptions, Extractor,
+};
+use crate::{
+    application_error::{Error, Result},
+    dlsite::progress::{DownloadPhase, ProgressReader},
+    storage::setting::ZipFilenameEncoding,
+};
+use encoding_rs::{Encoding, BIG5, EUC_KR, GBK, SHIFT_JIS};
+use std::{
+    fs::{create_dir_all, File},
+    io::{copy, BufReader, BufWriter, Read, Seek, Write},
+    path::Path,
+};
+use zip::{result::ZipError, ZipArchive};
+
+pub struct ZipExtractor;
+
+impl Extractor for ZipExtractor {
+    fn find_archives(
+        &self,
+        path: &Path,
+        file_names: &[&str],
+        source: ArchiveSource,
+    ) -> Vec<ArchiveSet> {
+        file_names
+            .iter()
+            .filter(|file_name| detect_format(path, file_name, source) == Some(ArchiveFormat::Zip))
+            .map(|file_name| ArchiveSet {
+                volumes: vec![file_name.to_string()],
+            })
+            .collect()
+    }
+
+    fn extract(
+        &self,
+        path: &Path,
+        archive: &ArchiveSet,
+        target_path: &Path,
+        context: &ExtractContext,
+    ) -> Result<()> {
+        extract_with_passwords(context, |password| {
+            let file = File::open(path.join(&archive.volumes[0]))
+                .map_err(|err| Error::ProductArchiveOpenError { io_error: err })?;
+            let archive_size = file
+                .metadata()
+                .map_err(|err| Error::ProductArchiveOpenError { io_error: err })?
+                .len();
+
+            context
+                .tracker
+                .begin_phase(DownloadPhase::Extracting, archive_size)?;
+            extract_zip(
+                ProgressReader::new(BufReader::new(file), context.token, context.tracker),
+                target_path,
+                &archive.volumes[0],
+                password,
+                context,
+            )
+        })
+    }
+
+    fn list_entries(
+        &self,
+        path: &Path,
+        archive: &ArchiveSet,
+        options: &ExtractOptions,
+    ) -> Result<Vec<ArchiveEntry>> {
+        let file = File::open(path.join(&archive.volumes[0]))
+            .map_err(|err| Error::ProductArchiveOpenError { io_error: err })?;
+
+        list_zip(BufReader::new(file), options.zip_filename_encoding)
+    }
+}
+
+/// Extracts every entry into `target_path`. Encrypted entries need `password`, while the others
+/// ignore it.
+pub fn extract_zip<R: Read + Seek>(
+    reader: R,
+    target_path: &Path,
+    file_name: &str,
+    password: Option<&str>,
+    context: &ExtractContext,
+) -> Result<()> {
+    let encoding = context.options.zip_filename_encoding;
+    let token = context.token;
+    let mut archive = ZipArchive::new(reader)
+        .map_err(|err| Error::ProductArchiveExtractError { extract_error: err })?;
+    let mut entries = Vec::with_capacity(archive.len());
+
+    for index in 0..archive.len() {
+        let entry = archive
+            .by_index_raw(index)
+            .map_err(|err| Error::ProductArchiveExtractError { extract_error: err })?;
+        let name = decode_entry_name(entry.name_raw(), entry.name(), encoding);
+
+        // Entries escaping the target directory are skipped, as `ZipFile::enclosed_name` would do.
+        if let Some(path) = context.paths.resolve(target_path, file_name, &name) {
+            entries.push((index, path, entry.is_dir()));
+        }
+    }
+
+    for (index, path, is_dir) in entries {
+        token.check()?;
+
+        if path.as_os_str().is_empty() {
+            continue;
+        }
+
+        let output_path = target_path.join(path);
+
+        if is_dir {
+            create_dir_all(&output_path)
+                .map_err(|err| Error::ProductArchiveWriteError { io_error: err })?;
+            continue;
+        }
+
+        if let Some(parent) = output_path.parent() {
+            create_dir_all(parent)
+                .map_err(|err| Error::ProductArchiveWriteError { io_error: err })?;
+        }
+
+        let entry = match password {
+            Some(password) => archive.by_index_decrypt(index, password.as_bytes()),
+            None => archive.by_index(index).map(Ok),
+        };
+        let mut entry = match entry {
+            Ok(Ok(entry)) => entry,
+            Ok(Err(_)) | Err(ZipError::UnsupportedArchive(ZipError::PASSWORD_REQUIRED)) => {
+                return Err(Error::ArchivePasswordRequired {
+                    file_name: file_name.to_owned(),
+                });
+            }
+            Err(err) => return Err(Error::ProductArchiveExtractError { extract_error: err }),
+        };
+        let mut writer = BufWriter::new(
+            File::create(&output_path)
+                .map_err(|err| Error::ProductArchiveWriteError { io_error: err })?,
+        );
+
+        if let Err(err) = copy(&mut entry, &mut writer) {
+            token.check()?;
+            return Err(Error::ProductArchiveWriteError { io_error: err });
+        }
+
+        writer
+            .flush()
+            .map_err(|err| Error::ProductArchiveWriteError { io_error: err })?;
+    }
+
+    Ok(())
+}
+
+/// Lists the entries from the central directory, which stays readable in encrypted archives.
+pub fn list_zip<R: Read + Seek>(
+    reader: R,
+    encoding: Option<ZipFilenameEncoding>,
+) -> Result<Vec<ArchiveEntry>> {
+    let mut archive = ZipArchive::new(reader)
+        .map_err(|err| Error::ProductArchiveExtractError { extract_error: err })?;
+
+    (0..archive.len())
+        .map(|index| {
+            let entry = archive
+                .by_index_raw(index)
+                .map_err(|err| Error::ProductArchiveExtractError { extract_error: err })?;
+
+            Ok(ArchiveEntry {
+                name: decode_entry_name(entry.name_raw(), entry.name(), encoding),
+                size: entry.size(),
+                is_dir: entry.is_dir(),
+            })
+        })
+        .collect()
+}
+
+fn decode_entry_name(raw_name: &[u8], name: &str, encoding: Option<ZipFilenameEncoding>) -> String {
+    // The zip crate decodes names with the UTF-8 flag as UTF-8 and the others as CP437, so both
+    // agree only for flagged or plain ASCII names, which never need a different encoding.
+    if std::str::from_utf8(raw_name) == Ok(name) {
+        return name.to_owned();
+    }
+
+    let encoding: &'static Encoding = match encoding {
+        Some(ZipFilenameEncoding::Utf8) => return String::from_utf8_lossy(raw_name).into_owned(),
+        Some(ZipFilenameEncoding::Cp437) => return name.to_owned(),
+        Some(ZipFilenameEncoding::Cp932) => SHIFT_JIS,
+        Some(ZipFilenameEncoding::Gbk) => GBK,
+        Some(ZipFilenameEncoding::Big5) => BIG5,
+        Some(ZipFilenameEncoding::EucKr) => EUC_KR,
+        None => return detect_entry_name(raw_name, name),
+    };
+
+    encoding
+        .decode_without_bom_handling(raw_name)
+        .0
+        .into_owned()
+}
+
+fn detect_entry_name(raw_name: &[u8], name: &str) -> String {
+    if let Ok(name) = std::str::from_utf8(raw_name) {
+        return name.to_owned();
+    }
+
+    // Most non-UTF-8 names in DLsite archives are CP932; anything else falls back to CP437.
+    match SHIFT_JIS.decode_without_bom_handling_and_without_replacement(raw_name) {
+        Some(name) => name.into_owned(),
+        None => name.to_owned(),
+    }
+}
@@ -0,0 +1,231 @@
+use super::extractor::{ArchiveEntry, ArchiveSource, ExtractOptions, ExtractorRegistry};
+use crate::application_error::{Error, Result};
+use serde::Serialize;
+use std::{
+    fs::{read_dir, DirEntry},
+    io::Result as IOResult,
+    path::Path,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub enum FileTreeNodeKind {
+    File,
+    Directory,
+    /// A set of archive volumes, whose children are the entries stored in it.
+    Archive,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct FileTreeNode {
+    pub name: String,
+    pub kind: FileTreeNodeKind,
+    /// Size on disk for files and archives, unpacked size for archive entries, and the sum of the
+    /// children for directories.
+    pub size: u64,
+    /// Set on archives that no known password opens, which are listed without their entries.
+    pub password_required: bool,
+    /// Set on archives whose entries cannot be listed for any other reason, e.g. damaged ones.
+    pub list_error: Option<String>,
+    pub children: Vec<FileTreeNode>,
+}
+
+impl FileTreeNode {
+    fn new(name: impl Into<String>, kind: FileTreeNodeKind, size: u64) -> Self {
+        Self {
+            name: name.into(),
+            kind,
+            size,
+            password_required: false,
+            list_error: None,
+            children: Vec::new(),
+        }
+    }
+}
+
+/// Lists the files under `path`, looking into the archives among them without extracting them.
+pub fn list_file_tree(path: &Path, options: &ExtractOptions) -> Result<FileTreeNode> {
+    let mut node = FileTreeNode::new(
+        path.file_name()
+            .unwrap_or_default()
+            .to_string_lossy()
+            .into_owned(),
+        FileTreeNodeKind::Directory,
+        0,
+    );
+
+    node.children = list_dir(&ExtractorRegistry::default(), path, options)?;
+    node.size = node.children.iter().map(|child| child.size).sum();
+    Ok(node)
+}
+
+fn list_dir(
+    registry: &ExtractorRegistry,
+    path: &Path,
+    options: &ExtractOptions,
+) -> Result<Vec<FileTreeNode>> {
+    let entries = read_dir(path)
+        .map_err(|err| Error::ProductArchiveOpenError { io_error: err })?
+        .collect::<IOResult<Vec<_>>>()
+        .map_err(|err| Error::ProductArchiveOpenError { io_error: err })?;
+    let mut nodes = Vec::new();
+    let mut files = Vec::new();
+
+    for entry in entries {
+        let name = entry.file_name().to_string_lossy().into_owned();
+
+        if entry
+            .file_type()
+            .map_err(|err| Error::ProductArchiveOpenError { io_error: err })?
+            .is_dir()
+        {
+            let mut node = FileTreeNode::new(name, FileTreeNodeKind::Directory, 0);
+            node.children = list_dir(registry, &entry.path(), options)?;
+            node.size = node.children.iter().map(|child| child.size).sum();
+            nodes.push(node);
+        } else {
+            files.push((name, get_file_size(&entry)?));
+        }
+    }
+
+    let file_names = files
+        .iter()
+        .map(|(name, _)| name.as_str())
+        .collect::<Vec<_>>();
+    let archives = registry.find_archives(path, &file_names, ArchiveSource::Extracted);
+    let mut listed = Vec::new();
+
+    for (extractor, archive) in archives {
+        let mut node = FileTreeNode::new(
+            archive.volumes[0].clone(),
+            FileTreeNodeKind::Archive,
+            files
+                .iter()
+                .filter(|(name, _)| archive.volumes.contains(name))
+                .map(|(_, size)| size)
+                .sum(),
+        );
+
+        match extractor.list_entries(path, &archive, options) {
+            Ok(entries) => node.children = build_entry_tree(entries),
+            Err(Error::ArchivePasswordRequired { .. }) => node.password_required = true,
+            Err(err) => node.list_error = Some(err.to_string()),
+        }
+
+        listed.extend(archive.volumes);
+        nodes.push(node);
+    }
+
+    nodes.extend(
+        files
+            .into_iter()
+            .filter(|(name, _)| !listed.contains(name))
+            .map(|(name, size)| FileTreeNode::new(name, FileTreeNodeKind::File, size)),
+    );
+    sort_nodes(&mut nodes);
+    Ok(nodes)
+}
+
+fn get_file_size(entry: &DirEntry) -> Result<u64> {
+    Ok(entry
+        .metadata()
+        .map_err(|err| Error::ProductArchiveOpenError { io_error: err })?
+        .len())
+}
+
+/// Nests the entries by their names, adding the directories that archives leave implicit.
+fn build_entry_tree(entries: Vec<ArchiveEntry>) -> Vec<FileTreeNode> {
+    let mut nodes = Vec::new();
+
+    for entry in entries {
+        let components = entry
+            .name
+            .split(['/', '\\'])
+            .filter(|component| !component.is_empty() && *component != ".")
+            .collect::<Vec<_>>();
+        let (name, parents) = match components.split_last() {
+            Some(split) => split,
+            None => continue,
+        };
+        let mut children = &mut nodes;
+
+        for parent in parents {
+            children = &mut get_or_insert_dir(children, parent).children;
+        }
+
+        if entry.is_dir {
+            get_or_insert_dir(children, name);
+        } else {
+            children.push(FileTreeNode::new(*name, FileTreeNodeKind::File, entry.size));
+        }
+    }
+
+    for node in &mut nodes {
+        sum_dir_sizes(node);
+    }
+
+    sort_nodes(&mut nodes);
+    nodes
+}
+
+fn get_or_insert_dir<'a>(nodes: &'a mut Vec<FileTreeNode>, name: &str) -> &'a mut FileTreeNode {
+    let index = match nodes
+        .iter()
+        .position(|node| node.kind == FileTreeNodeKind::Directory && node.name == name)
+    {
+        Some(index) => index,
+        None => {
+            nodes.push(FileTreeNode::new(name, FileTreeNodeKind::Directory, 0));
+            nodes.len() - 1
+        }
+    };
+
+    &mut nodes[index]
+}
+
+fn sum_dir_sizes(node: &mut FileTreeNode) {
+    if node.kind != FileTreeNodeKind::Directory {
+        return;
+    }
+
+    for child in &mut node.children {
+        sum_dir_sizes(child);
+    }
+
+    sort_nodes(&mut node.children);
+    node.size = node.children.iter().map(|child| child.size).sum();
+}
+
+/// Directories come first, then everything else by name.
+fn sort_nodes(nodes: &mut [FileTreeNode]) {
+    nodes.sort_by(|a, b| {
+        (a.kind != FileTreeNodeKind::Directory, &a.name)
+            .cmp(&(b.kind != FileTreeNodeKind::Directory, &b.name))
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::{create_dir_all, write};
+
+    #[test]
+    fn archives_that_cannot_be_listed_do_not_fail_the_tree() {
+        let dir = tempfile::tempdir().unwrap();
+        let sub_path = dir.path().join("sub");
+
+        create_dir_all(&sub_path).unwrap();
+        write(dir.path().join("broken.zip"), b"PK\x03\x04broken").unwrap();
+        write(sub_path.join("readme.txt"), b"readme").unwrap();
+
+        let tree = list_file_tree(dir.path(), &ExtractOptions::default()).unwrap();
+        let broken = tree
+            .children
+            .iter()
+            .find(|node| node.name == "broken.zip")
+            .unwrap();
+
+        assert_eq!(broken.kind, FileTreeNodeKind::Archive);
+        assert!(broken.list_error.is_some());
+        assert!(tree.children.iter().any(|node| node.name == "sub"));
+    }
+}
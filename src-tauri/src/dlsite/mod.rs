@@ -1,26 +1,61 @@
 pub mod api;
+mod extraction;
+pub mod extractor;
+mod file_tree;
+mod progress;
+mod rate_limiter;
+mod token;
+mod transfer;
+
+pub use extraction::extract_downloaded_product;
+pub use file_tree::{list_file_tree, FileTreeNode, FileTreeNodeKind};
+pub use progress::{DownloadPhase, DownloadProgress};
+pub use rate_limiter::RateLimiter;
+pub use token::DownloadToken;
 
 use crate::{
-    application_error::{Error, Result},
-    dlsite::api::DLsiteProductDetail,
+    application_error::{ApplicationErrorKind, Error, Result},
+    dlsite::{
+        api::{DLsiteProductDetailContent, DLsiteProductList},
+        extraction::extract_product,
+        extractor::ExtractOptions,
+        progress::ProgressTracker,
+        transfer::RemoteFile,
+    },
     storage::{
         account::Account,
         product::{InsertedProduct, Product},
+        setting::Setting,
     },
 };
+use chrono::{DateTime, Utc};
+use fs4::available_space;
+use futures::stream::{self, StreamExt};
+use parking_lot::Mutex;
 use reqwest::ClientBuilder;
 use reqwest_cookie_store::{CookieStore, CookieStoreMutex};
 use std::{
-    fs::{create_dir_all, read_dir, remove_dir_all, remove_file, rename, OpenOptions},
-    io::{BufReader, BufWriter, Result as IOResult, Write},
+    collections::HashSet,
+    fs::{create_dir_all, remove_dir_all, rename},
+    future::Future,
+    io::BufWriter,
     path::{Path, PathBuf},
     sync::Arc,
-    time::{Duration, Instant},
 };
-use unrar::Archive;
+use tauri::async_runtime::spawn_blocking;
 use log::error;
 
 static PAGE_LIMIT: usize = 50;
+static STAGING_DIR_NAME: &str = ".staging";
+
+#[derive(Debug, Clone)]
+pub struct DownloadOptions {
+    pub decompress: bool,
+    pub max_retries: u32,
+    pub rate_limiter: Arc<RateLimiter>,
+    pub segments: usize,
+    pub extract: ExtractOptions,
+}
 
 macro_rules! with_cookie_store {
     ($account_id:ident, $f:ident) => {
@@ -89,169 +124,420 @@ async fn get_product_count_and_cookie_store(
     with_cookie_store!(account_id, body);
 }
 
-async fn get_product_details_and_cookie_store(
-    account_id: i64,
-    product_id: impl AsRef<str>,
-) -> Result<(Vec<DLsiteProductDetail>, Arc<CookieStoreMutex>)> {
-    async fn body(
-        product_id: impl AsRef<str>,
-        cookie_store: Arc<CookieStoreMutex>,
-    ) -> Result<(Vec<DLsiteProductDetail>, Arc<CookieStoreMutex>)> {
-        Ok((
-            api::get_product_details(/*cookie_store.clone(),*/ product_id).await?,
-            cookie_store,
-        ))
+/// Sums up the progress of the accounts that are synced at once.
+struct SyncProgress<F> {
+    progress: Vec<usize>,
+    total_progress: usize,
+    on_progress: F,
+}
+
+impl<F: FnMut(usize, usize) -> Result<()>> SyncProgress<F> {
+    fn new(total_progress: usize, account_count: usize, on_progress: F) -> Self {
+        Self {
+            progress: vec![0; account_count],
+            total_progress,
+            on_progress,
+        }
     }
 
-    let body = |cookie_store: Arc<CookieStoreMutex>| body(product_id.as_ref(), cookie_store);
+    fn set(&mut self, index: usize, progress: usize) -> Result<()> {
+        self.progress[index] = progress;
+        (self.on_progress)(self.progress.iter().sum(), self.total_progress)
+    }
 
-    with_cookie_store!(account_id, body);
+    fn report(&mut self) -> Result<()> {
+        (self.on_progress)(self.progress.iter().sum(), self.total_progress)
+    }
 }
 
-pub async fn update_product(mut on_progress: impl FnMut(usize, usize) -> Result<()>) -> Result<()> {
-    let account_ids = Account::list_all_id()?;
-    let mut progress = 0;
-    let mut total_progress = 0;
-    let mut details = Vec::with_capacity(account_ids.len());
-
-    for account_id in account_ids {
-        let prev_product_count =
-            Account::get_one_product_count(account_id)?.unwrap_or_else(|| 0) as usize;
-        let (new_product_count, cookie_store) =
-            match get_product_count_and_cookie_store(account_id).await {
-                Ok(product_count_and_cookie_store) => product_count_and_cookie_store,
-                Err(err) => match err {
-                    Error::DLsiteNotAuthenticated => continue,
-                    _ => return Err(err),
-                },
-            };
-
-        if new_product_count <= prev_product_count {
-            continue;
+/// Keeps the results of the accounts that succeeded. Accounts that cannot sign in are skipped as
+/// they always were, while other failures are logged and collected into `errors`.
+fn filter_account_results<T>(
+    results: Vec<(i64, Result<T>)>,
+    errors: &mut Vec<Error>,
+) -> Vec<(i64, T)> {
+    let mut succeeded = Vec::with_capacity(results.len());
+
+    for (account_id, result) in results {
+        match result {
+            Ok(result) => succeeded.push((account_id, result)),
+            Err(Error::DLsiteNotAuthenticated) => {}
+            Err(err) => {
+                error!("sync of Account(id='{}') failed: {}", account_id, err);
+                errors.push(err);
+            }
         }
+    }
 
-        log::error!("Products update {} -> {}", prev_product_count, new_product_count);
+    succeeded
+}
 
-        total_progress += new_product_count - prev_product_count;
-        details.push((
-            account_id,
-            prev_product_count,
-            new_product_count,
-            cookie_store,
-        ));
+fn into_first_error(errors: Vec<Error>) -> Result<()> {
+    match errors.into_iter().next() {
+        Some(err) => Err(err),
+        None => Ok(()),
     }
+}
 
-    if total_progress == 0 {
-        return Ok(());
+/// Walks the purchases of every account whose list changed since its last sync, adding the new
+/// products and revoking the ones that are gone. Accounts are synced concurrently, and a failing
+/// account leaves the others to finish before its error is returned.
+pub async fn update_product(on_progress: impl FnMut(usize, usize) -> Result<()>) -> Result<()> {
+    let setting = Setting::get()?;
+    let mut errors = Vec::new();
+    let results = stream::iter(Account::list_all_id()?)
+        .map(|account_id| async move { (account_id, get_changed_purchases(account_id).await) })
+        .buffer_unordered(setting.sync_concurrent_accounts())
+        .collect::<Vec<_>>()
+        .await;
+    let details = filter_account_results(results, &mut errors)
+        .into_iter()
+        .filter_map(|(account_id, changed)| {
+            changed.map(|(product_count, cookie_store, first_page)| {
+                (account_id, product_count, cookie_store, first_page)
+            })
+        })
+        .collect::<Vec<_>>();
+
+    if details.is_empty() {
+        return into_first_error(errors);
     }
 
-    on_progress(progress, total_progress)?;
-
-    for (account_id, mut prev_product_count, new_product_count, cookie_store) in details {
-        while prev_product_count < new_product_count {
-            let page = 1 + prev_product_count / PAGE_LIMIT;
-            let products = match api::get_product(cookie_store.clone(), page).await {
-                Ok(products) => products,
-                Err(err) => match err {
-                    Error::DLsiteNotAuthenticated => {
-                        progress += new_product_count - prev_product_count;
-                        on_progress(progress, total_progress)?;
-                        break;
-                    }
-                    _ => return Err(err),
-                },
-            };
+    let progress = Mutex::new(SyncProgress::new(
+        details
+            .iter()
+            .map(|(_, product_count, ..)| product_count)
+            .sum(),
+        details.len(),
+        on_progress,
+    ));
+    progress.lock().report()?;
+
+    let results = stream::iter(details.into_iter().enumerate())
+        .map(
+            |(index, (account_id, product_count, cookie_store, first_page))| {
+                let progress = &progress;
+                let setting = &setting;
+
+                async move {
+                    let result = update_account_products(
+                        account_id,
+                        product_count,
+                        cookie_store,
+                        first_page,
+                        setting.sync_concurrent_pages(),
+                        |account_progress| progress.lock().set(index, account_progress),
+                    )
+                    .await;
+                    // Failed accounts are counted as done, so that the total is still reached.
+                    let reported = progress.lock().set(index, product_count);
 
-            let updated_prev_product_count = (page - 1) * PAGE_LIMIT + products.len();
-            progress += updated_prev_product_count - prev_product_count;
-            prev_product_count = updated_prev_product_count;
+                    (account_id, result.and(reported))
+                }
+            },
+        )
+        .buffer_unordered(setting.sync_concurrent_accounts())
+        .collect::<Vec<_>>()
+        .await;
 
-            on_progress(progress, total_progress)?;
+    filter_account_results(results, &mut errors);
+    into_first_error(errors)
+}
 
-            Product::insert_all(products.into_iter().map(|product| InsertedProduct {
-                account_id,
-                product,
-            }))?;
-        }
+/// Signs in and looks at the first page of purchases, giving `None` if neither the count nor the
+/// `last` timestamp changed since the last sync.
+async fn get_changed_purchases(
+    account_id: i64,
+) -> Result<Option<(usize, Arc<CookieStoreMutex>, DLsiteProductList)>> {
+    let prev_product_count =
+        Account::get_one_product_count(account_id)?.unwrap_or_else(|| 0) as usize;
+    let (new_product_count, cookie_store) = get_product_count_and_cookie_store(account_id).await?;
+    let first_page = api::get_product_list(cookie_store.clone(), 1).await?;
+
+    // A refund followed by a purchase keeps the count, but not the `last` timestamp.
+    if new_product_count == prev_product_count
+        && Account::get_one_purchases_synced_last(account_id)? == Some(first_page.last)
+    {
+        return Ok(None);
     }
 
-    Ok(())
+    Ok(Some((new_product_count, cookie_store, first_page)))
 }
 
-pub async fn refresh_product(
-    mut on_progress: impl FnMut(usize, usize) -> Result<()>,
+async fn update_account_products(
+    account_id: i64,
+    product_count: usize,
+    cookie_store: Arc<CookieStoreMutex>,
+    first_page: DLsiteProductList,
+    concurrent_pages: usize,
+    on_progress: impl Fn(usize) -> Result<()>,
 ) -> Result<()> {
-    Product::remove_all()?;
+    let last = first_page.last;
+    let limit = first_page.limit;
+    let mut product_ids = HashSet::new();
+    let mut products = first_page.works;
+    // Pages past the end are fetched ahead at most `concurrent_pages` times and dropped unread.
+    let mut pages = stream::iter(2..)
+        .map(|page| api::get_product(cookie_store.clone(), page))
+        .buffered(concurrent_pages);
+
+    let is_complete = loop {
+        let fetched_count = product_ids.len();
+        let page_len = products.len();
+
+        product_ids.extend(products.iter().map(|product| product.id.clone()));
+        Product::insert_all(products.into_iter().map(|product| InsertedProduct {
+            account_id,
+            product,
+        }))?;
 
-    let account_ids = Account::list_all_id()?;
-    let mut progress = 0;
-    let mut total_progress = 0;
-    let mut details = Vec::with_capacity(account_ids.len());
+        on_progress(product_ids.len().min(product_count))?;
 
-    for account_id in account_ids {
-        let (new_product_count, cookie_store) =
-            match get_product_count_and_cookie_store(account_id).await {
-                Ok(product_count_and_cookie_store) => product_count_and_cookie_store,
-                Err(err) => match err {
-                    Error::DLsiteNotAuthenticated => continue,
-                    _ => return Err(err),
-                },
-            };
+        if page_len < limit.max(1) {
+            break true;
+        }
 
-        if new_product_count == 0 {
-            continue;
+        // A full page that adds nothing new means the page is being ignored, so the rest of the
+        // list is unknown.
+        if product_ids.len() == fetched_count {
+            break false;
         }
 
-        total_progress += new_product_count;
-        details.push((account_id, new_product_count, cookie_store));
+        products = match pages.next().await {
+            Some(products) => products?,
+            None => break false,
+        };
+    };
+
+    // Products are only revoked once the whole list has been seen, and the list is fetched again
+    // from the same point next time otherwise.
+    if is_complete {
+        Product::update_all_revoked(account_id, &product_ids)?;
+        Account::update_one_purchases_synced_last(account_id, last)?;
     }
 
-    if total_progress == 0 {
-        return Ok(());
+    Ok(())
+}
+
+/// Fetches the purchases of every account into a staging table and merges them at the end, so that
+/// a failed refresh leaves the products, their downloads and cached details as they were. Accounts
+/// are fetched concurrently, and only the ones that were walked to the end lose their missing
+/// products.
+pub async fn refresh_product(on_progress: impl FnMut(usize, usize) -> Result<()>) -> Result<()> {
+    let setting = Setting::get()?;
+    let mut errors = Vec::new();
+    let results = stream::iter(Account::list_all_id()?)
+        .map(|account_id| async move {
+            (
+                account_id,
+                get_product_count_and_cookie_store(account_id).await,
+            )
+        })
+        .buffer_unordered(setting.sync_concurrent_accounts())
+        .collect::<Vec<_>>()
+        .await;
+    let details = filter_account_results(results, &mut errors)
+        .into_iter()
+        .map(|(account_id, (product_count, cookie_store))| {
+            (account_id, product_count, cookie_store)
+        })
+        .collect::<Vec<_>>();
+
+    if details.is_empty() {
+        return into_first_error(errors);
     }
 
-    on_progress(progress, total_progress)?;
+    Product::begin_staging()?;
+
+    let progress = Mutex::new(SyncProgress::new(
+        details
+            .iter()
+            .map(|(_, product_count, _)| product_count)
+            .sum(),
+        details.len(),
+        on_progress,
+    ));
+    progress.lock().report()?;
+
+    let results = stream::iter(details.into_iter().enumerate())
+        .map(|(index, (account_id, product_count, cookie_store))| {
+            let progress = &progress;
+            let setting = &setting;
+
+            async move {
+                let result = stage_account_products(
+                    account_id,
+                    product_count,
+                    cookie_store,
+                    setting.sync_concurrent_pages(),
+                    |account_progress| progress.lock().set(index, account_progress),
+                )
+                .await;
+                let reported = progress.lock().set(index, product_count);
+
+                (account_id, result.and_then(|last| reported.map(|()| last)))
+            }
+        })
+        .buffer_unordered(setting.sync_concurrent_accounts())
+        .collect::<Vec<_>>()
+        .await;
+
+    // Products of accounts that could not be walked to the end are left alone.
+    let synced_accounts = filter_account_results(results, &mut errors);
+
+    Product::commit_staged(
+        &synced_accounts
+            .iter()
+            .map(|(account_id, _)| *account_id)
+            .collect::<Vec<_>>(),
+    )?;
+
+    for (account_id, last) in synced_accounts {
+        if let Some(last) = last {
+            Account::update_one_purchases_synced_last(account_id, last)?;
+        }
+    }
 
-    for (account_id, new_product_count, cookie_store) in details {
-        let mut prev_product_count = 0;
+    into_first_error(errors)
+}
 
-        while prev_product_count < new_product_count {
-            let page = 1 + prev_product_count / PAGE_LIMIT;
-            let products = match api::get_product(cookie_store.clone(), page).await {
-                Ok(products) => products,
-                Err(err) => match err {
-                    Error::DLsiteNotAuthenticated => {
-                        progress += new_product_count - prev_product_count;
-                        on_progress(progress, total_progress)?;
-                        break;
-                    }
-                    _ => return Err(err),
-                },
-            };
-            prev_product_count += products.len();
-            progress += products.len();
+/// Stages every page of purchases of the account, giving the `last` timestamp of the list.
+async fn stage_account_products(
+    account_id: i64,
+    product_count: usize,
+    cookie_store: Arc<CookieStoreMutex>,
+    concurrent_pages: usize,
+    on_progress: impl Fn(usize) -> Result<()>,
+) -> Result<Option<DateTime<Utc>>> {
+    let page_count = (product_count + PAGE_LIMIT - 1) / PAGE_LIMIT;
+    let mut pages = stream::iter(1..=page_count)
+        .map(|page| api::get_product_list(cookie_store.clone(), page))
+        .buffered(concurrent_pages);
+    let mut staged_count = 0;
+    let mut last = None;
+
+    while let Some(product_list) = pages.next().await {
+        let product_list = product_list?;
+        let products = product_list.works;
+
+        if products.is_empty() {
+            break;
+        }
+
+        last.get_or_insert(product_list.last);
+        staged_count += products.len();
+
+        Product::stage_all(products.into_iter().map(|product| InsertedProduct {
+            account_id,
+            product,
+        }))?;
+        on_progress(staged_count.min(product_count))?;
+    }
 
-            on_progress(progress, total_progress)?;
+    Ok(last)
+}
 
-            Product::insert_all(products.into_iter().map(|product| InsertedProduct {
+/// Downloads the product with the given account, falling back to the other accounts that own it
+/// for as long as the account cannot sign in. An expired session is renewed once before the next
+/// account is tried, and files that are already downloaded are kept between the attempts.
+pub async fn download_product(
+    options: DownloadOptions,
+    token: &DownloadToken,
+    account_id: i64,
+    product_id: impl AsRef<str>,
+    base_path: impl AsRef<Path>,
+    on_progress: impl Fn(DownloadProgress) -> Result<()> + Send + Sync + 'static,
+) -> Result<PathBuf> {
+    let fallback_account_ids = Product::list_owner_account_ids(product_id.as_ref())?
+        .into_iter()
+        .filter(|&owner_id| owner_id != account_id);
+    let on_progress: Arc<dyn Fn(DownloadProgress) -> Result<()> + Send + Sync> =
+        Arc::new(on_progress);
+
+    download_with_fallback(
+        product_id.as_ref(),
+        token,
+        account_id,
+        fallback_account_ids,
+        |account_id| {
+            download_product_as(
+                &options,
+                token,
                 account_id,
-                product,
-            }))?;
+                product_id.as_ref(),
+                base_path.as_ref(),
+                on_progress.clone(),
+            )
+        },
+    )
+    .await
+}
+
+async fn download_with_fallback<F: Future<Output = Result<PathBuf>>>(
+    product_id: &str,
+    token: &DownloadToken,
+    account_id: i64,
+    fallback_account_ids: impl IntoIterator<Item = i64>,
+    mut download: impl FnMut(i64) -> F,
+) -> Result<PathBuf> {
+    let mut tried_account_id = account_id;
+    let mut result = download(account_id).await;
+
+    for fallback_account_id in fallback_account_ids {
+        match &result {
+            // Other failures, like a removed file, would be the same with every account.
+            Err(err) if err.kind() == ApplicationErrorKind::Authentication => {
+                error!(
+                    "download of {} with Account(id='{}') failed, trying Account(id='{}'): {}",
+                    product_id, tried_account_id, fallback_account_id, err
+                );
+            }
+            _ => break,
         }
+
+        token.check()?;
+        tried_account_id = fallback_account_id;
+        result = download(fallback_account_id).await;
     }
 
-    Ok(())
+    result
 }
 
-pub async fn download_product(
-    decompress: bool,
+/// Downloads the product with the saved session of the account, signing in again once if the
+/// session turns out to be expired.
+async fn download_product_as(
+    options: &DownloadOptions,
+    token: &DownloadToken,
     account_id: i64,
+    product_id: &str,
+    base_path: &Path,
+    on_progress: Arc<dyn Fn(DownloadProgress) -> Result<()> + Send + Sync>,
+) -> Result<PathBuf> {
+    let body = |cookie_store: Arc<CookieStoreMutex>| {
+        download_product_with(
+            options,
+            token,
+            product_id,
+            base_path,
+            on_progress.clone(),
+            cookie_store,
+        )
+    };
+
+    with_cookie_store!(account_id, body);
+}
+
+async fn download_product_with(
+    options: &DownloadOptions,
+    token: &DownloadToken,
     product_id: impl AsRef<str>,
     base_path: impl AsRef<Path>,
-    on_progress: impl Fn(u64, u64) -> Result<()>,
+    on_progress: Arc<dyn Fn(DownloadProgress) -> Result<()> + Send + Sync>,
+    cookie_store: Arc<CookieStoreMutex>,
 ) -> Result<PathBuf> {
-    let (details, cookie_store) =
-        get_product_details_and_cookie_store(account_id, product_id.as_ref()).await?;
+    // The details are served without a session, so an expired one only shows once the files are
+    // requested.
+    let details = api::get_product_details(product_id.as_ref()).await?;
 
     if details.len() != 1 {
         return Err(Error::DLsiteProductDetailMissingOrNotUnique);
@@ -283,183 +569,206 @@ pub async fn download_product(
         }
     }
 
-    let path = base_path.as_ref().join(product_id.as_ref());
-
-    if path.exists() {
-        remove_dir_all(&path).map_err(|err| Error::ProductDirCreationError { io_error: err })?;
-    }
+    let path = get_staging_path(product_id.as_ref(), base_path.as_ref());
 
     create_dir_all(&path).map_err(|err| Error::ProductDirCreationError { io_error: err })?;
-    on_progress(0, file_size)?;
+    check_disk_space(
+        &path,
+        get_required_space(&detail.contents, &path, options.decompress),
+    )?;
+
+    // The tracker is shared with the extraction, which runs on a thread of its own.
+    let tracker = Arc::new(ProgressTracker::new(
+        file_size,
+        detail.contents.len(),
+        move |progress| on_progress(progress),
+    ));
+    tracker.report()?;
 
-    let mut progress = 0;
     let client = ClientBuilder::new()
         .cookie_store(true)
         .cookie_provider(cookie_store)
         .build()?;
 
-    for (index, file_url) in file_urls.into_iter().enumerate() {
-        let file = OpenOptions::new()
-            .write(true)
-            .create_new(true)
-            .open(path.join(&detail.contents[index].file_name))
-            .map_err(|err| Error::ProductFileCreationError { io_error: err })?;
-        let mut writer = BufWriter::with_capacity(1 * 1024 * 1024, file);
-        let mut last_progress_time = Instant::now();
-        let mut process_per_file = 0;
-
-        'req: loop {
-            let mut response = match client
-                .get(&file_url)
-                .header("range", format!("bytes={}-", process_per_file))
-                .send()
-                .await
-            {
-                Ok(response) => response,
-                Err(_) => {
-                    continue;
-                }
-            };
+    for (index, file_url) in file_urls.iter().enumerate() {
+        let content = &detail.contents[index];
+        let file_path = path.join(&content.file_name);
+
+        tracker.begin_file(index, &content.file_name)?;
+        transfer::download_file(
+            &client,
+            options,
+            token,
+            &RemoteFile {
+                url: file_url,
+                name: &content.file_name,
+                path: &file_path,
+                size: content.file_size.parse::<u64>().unwrap(),
+            },
+            &tracker,
+        )
+        .await?;
+    }
 
-            while let Some(chunk) = match response.chunk().await {
-                Ok(chunk) => chunk,
-                Err(_) => {
-                    continue 'req;
-                }
-            } {
-                writer
-                    .write_all(&chunk)
-                    .map_err(|err| Error::ProductFileWriteError { io_error: err })?;
-                progress += chunk.len();
-                process_per_file += chunk.len();
-
-                let now = Instant::now();
-
-                if Duration::from_secs(1) <= now - last_progress_time {
-                    last_progress_time = now;
-                    on_progress(progress as u64, file_size)?;
-                }
-            }
+    tracker.finish()?;
+
+    let file_names = detail
+        .contents
+        .into_iter()
+        .map(|content| content.file_name)
+        .collect::<Vec<_>>();
+    let options = options.clone();
+    let product_id = product_id.as_ref().to_owned();
+    let base_path = base_path.as_ref().to_owned();
+    let token = token.clone();
+
+    // Extraction blocks for as long as the archives take, so it is kept off the runtime that runs
+    // the other downloads.
+    spawn_blocking(move || {
+        let file_names = file_names
+            .iter()
+            .map(|file_name| file_name.as_str())
+            .collect::<Vec<_>>();
+
+        install_staged_product(
+            &options,
+            &file_names,
+            &product_id,
+            &base_path,
+            &token,
+            &tracker,
+        )
+    })
+    .await?
+}
 
-            writer
-                .flush()
-                .map_err(|err| Error::ProductFileWriteError { io_error: err })?;
-            break;
-        }
+/// Extracts the downloaded archives in the staging folder if asked to, then swaps it with the live
+/// one. A failed extraction, e.g. of archives whose password is not known yet, leaves the archives
+/// staged and the live folder untouched.
+fn install_staged_product(
+    options: &DownloadOptions,
+    file_names: &[&str],
+    product_id: &str,
+    base_path: &Path,
+    token: &DownloadToken,
+    tracker: &ProgressTracker,
+) -> Result<PathBuf> {
+    if options.decompress {
+        token.check()?;
+        extract_product(
+            &options.extract,
+            file_names,
+            &get_staging_path(product_id, base_path),
+            token,
+            tracker,
+        )?;
     }
 
-    on_progress(file_size, file_size)?;
+    commit_staged_product(product_id, base_path)?;
+    Ok(base_path.join(product_id))
+}
 
-    if !decompress {
-        return Ok(path);
-    }
+pub fn get_available_space(path: impl AsRef<Path>) -> Result<u64> {
+    // The download directory may not exist yet, so the nearest existing ancestor decides the volume.
+    let path = path
+        .as_ref()
+        .ancestors()
+        .find(|path| path.exists())
+        .unwrap_or_else(|| path.as_ref());
 
-    if detail.contents.len() == 1 && detail.contents[0].file_name.ends_with(".zip") {
-        let tmp_path = path.join("__tmp__");
-        let file_path = path.join(&detail.contents[0].file_name);
-        let file = OpenOptions::new()
-            .read(true)
-            .open(&file_path)
-            .map_err(|err| Error::ProductArchiveOpenError { io_error: err })?;
-        let reader = BufReader::new(file);
+    available_space(path).map_err(|err| Error::DiskSpaceQueryError { io_error: err })
+}
 
-        zip_extract::extract(reader, &tmp_path, true)
-            .map_err(|err| Error::ProductArchiveExtractError { extract_error: err })?;
+pub fn check_disk_space(path: impl AsRef<Path>, required: u64) -> Result<()> {
+    let available = get_available_space(path)?;
 
-        remove_file(&file_path)
-            .map_err(|err| Error::ProductArchiveDeleteError { io_error: err })?;
+    if available < required {
+        return Err(Error::InsufficientDiskSpace {
+            required,
+            available,
+        });
+    }
 
-        for content_path in read_dir(&tmp_path)
-            .map_err(|err| Error::ProductArchiveCleanupError { io_error: err })?
-        {
-            let content_path = content_path
-                .map_err(|err| Error::ProductArchiveCleanupError { io_error: err })?
-                .path();
+    Ok(())
+}
 
-            rename(
-                &content_path,
-                path.join(content_path.strip_prefix(&tmp_path).unwrap()),
-            )
-            .map_err(|err| Error::ProductArchiveCleanupError { io_error: err })?;
-        }
+// Counts what is still left to download, plus the extracted copy that exists next to the archives
+// until they are removed.
+fn get_required_space(
+    contents: &[DLsiteProductDetailContent],
+    path: &Path,
+    decompress: bool,
+) -> u64 {
+    contents
+        .iter()
+        .map(|content| {
+            let size = content.file_size.parse::<u64>().unwrap();
+            let downloaded = transfer::get_downloaded_size(&path.join(&content.file_name), size);
+
+            if decompress {
+                size - downloaded + size
+            } else {
+                size - downloaded
+            }
+        })
+        .sum()
+}
 
-        remove_dir_all(&tmp_path)
-            .map_err(|err| Error::ProductArchiveCleanupError { io_error: err })?;
-    }
+pub fn get_staging_path(product_id: impl AsRef<str>, base_path: impl AsRef<Path>) -> PathBuf {
+    base_path
+        .as_ref()
+        .join(STAGING_DIR_NAME)
+        .join(product_id.as_ref())
+}
 
-    if detail.contents.len() != 0 && detail.contents[0].file_name.ends_with(".exe") {
-        let rar_filename = path
-            .join(&detail.contents[0].file_name)
-            .with_extension("rar");
-
-        rename(path.join(&detail.contents[0].file_name), &rar_filename)
-            .map_err(|err| Error::ProductRarArchiveRenameError { io_error: err })?;
-
-        let tmp_path = path.join("__tmp__");
-        let mut archive = Archive::new(
-            &rar_filename
-                .to_str()
-                .ok_or_else(|| Error::NonUtf8PathError {
-                    path: rar_filename.clone(),
-                })?
-                .to_owned(),
-        )
-        .open_for_processing()
-        .map_err(|err| Error::ProductRarArchiveExtractOpenError { extract_error: err })?;
+// Swaps the finished staging directory with the live one, so a failure at any point leaves the
+// previous copy of the product untouched.
+pub fn commit_staged_product(
+    product_id: impl AsRef<str>,
+    base_path: impl AsRef<Path>,
+) -> Result<()> {
+    let staging_path = get_staging_path(product_id.as_ref(), base_path.as_ref());
+    let backup_path = get_staging_path(format!("{}.old", product_id.as_ref()), base_path.as_ref());
+    let path = base_path.as_ref().join(product_id.as_ref());
 
-        while let Some(header) = archive
-            .read_header()
-            .map_err(|err| Error::ProductRarArchiveExtractProcessError { extract_error: err })?
-        {
-            archive = header.extract_with_base(&tmp_path).map_err(|err| {
-                Error::ProductRarArchiveExtractProcessError { extract_error: err }
-            })?;
-        }
+    if backup_path.exists() {
+        remove_dir_all(&backup_path)
+            .map_err(|err| Error::ProductStagingCommitError { io_error: err })?;
+    }
 
-        rename(&rar_filename, path.join(&detail.contents[0].file_name))
-            .map_err(|err| Error::ProductRarArchiveRenameError { io_error: err })?;
+    let has_backup = path.exists();
 
-        for content in &detail.contents {
-            remove_file(path.join(&content.file_name))
-                .map_err(|err| Error::ProductArchiveDeleteError { io_error: err })?;
-        }
+    if has_backup {
+        rename(&path, &backup_path)
+            .map_err(|err| Error::ProductStagingCommitError { io_error: err })?;
+    }
 
-        let mut content_paths = read_dir(&tmp_path)
-            .map_err(|err| Error::ProductArchiveCleanupError { io_error: err })?
-            .collect::<IOResult<Vec<_>>>()
-            .map_err(|err| Error::ProductArchiveCleanupError { io_error: err })?;
-        let content_prefix_path;
-
-        if content_paths.len() == 1
-            && content_paths[0]
-                .file_type()
-                .map_err(|err| Error::ProductArchiveCleanupError { io_error: err })?
-                .is_dir()
-        {
-            content_prefix_path = content_paths[0].path();
-            content_paths = read_dir(content_paths[0].path())
-                .map_err(|err| Error::ProductArchiveCleanupError { io_error: err })?
-                .collect::<IOResult<Vec<_>>>()
-                .map_err(|err| Error::ProductArchiveCleanupError { io_error: err })?;
-        } else {
-            content_prefix_path = tmp_path.clone();
+    if let Err(err) = rename(&staging_path, &path) {
+        if has_backup {
+            rename(&backup_path, &path).ok();
         }
 
-        for content_path in content_paths {
-            let content_path = content_path.path();
+        return Err(Error::ProductStagingCommitError { io_error: err });
+    }
 
-            rename(
-                &content_path,
-                path.join(content_path.strip_prefix(&content_prefix_path).unwrap()),
-            )
-            .map_err(|err| Error::ProductArchiveCleanupError { io_error: err })?;
-        }
+    if has_backup {
+        remove_dir_all(&backup_path).ok();
+    }
+
+    Ok(())
+}
 
-        remove_dir_all(&tmp_path)
-            .map_err(|err| Error::ProductArchiveCleanupError { io_error: err })?;
+pub fn remove_staged_product(
+    product_id: impl AsRef<str>,
+    base_path: impl AsRef<Path>,
+) -> Result<()> {
+    let path = get_staging_path(product_id, base_path);
+
+    if path.exists() {
+        remove_dir_all(&path).map_err(|err| Error::ProductDirCreationError { io_error: err })?;
     }
 
-    Ok(path)
+    Ok(())
 }
 
 pub fn remove_downloaded_product(
@@ -470,3 +779,123 @@ pub fn remove_downloaded_product(
     remove_dir_all(&path).map_err(|err| Error::ProductDirCreationError { io_error: err })?;
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::executor::block_on;
+    use std::fs::{copy, read, read_dir, write};
+
+    // Lists every file under `path` with its contents.
+    fn read_all(path: &Path) -> Vec<(PathBuf, Vec<u8>)> {
+        let mut files = Vec::new();
+
+        for entry in read_dir(path).unwrap() {
+            let path = entry.unwrap().path();
+
+            if path.is_dir() {
+                files.extend(read_all(&path));
+            } else {
+                files.push((path.clone(), read(&path).unwrap()));
+            }
+        }
+
+        files.sort();
+        files
+    }
+
+    #[test]
+    fn archives_waiting_for_a_password_leave_the_live_copy_untouched() {
+        let dir = tempfile::tempdir().unwrap();
+        let live_path = dir.path().join("RJ000001");
+        let staging_path = get_staging_path("RJ000001", dir.path());
+
+        create_dir_all(live_path.join("save")).unwrap();
+        write(live_path.join("Game.exe"), b"game").unwrap();
+        write(live_path.join("save").join("data.sav"), b"save").unwrap();
+        create_dir_all(&staging_path).unwrap();
+        // Made with `zip -P`, so that it cannot be opened without a password.
+        copy(
+            Path::new(env!("CARGO_MANIFEST_DIR"))
+                .join("tests")
+                .join("fixtures")
+                .join("encrypted-zip")
+                .join("product.zip"),
+            staging_path.join("product.zip"),
+        )
+        .unwrap();
+
+        let live_files = read_all(&live_path);
+        let options = DownloadOptions {
+            decompress: true,
+            max_retries: 0,
+            rate_limiter: Arc::new(RateLimiter::new(None)),
+            segments: 1,
+            extract: ExtractOptions::default(),
+        };
+        let token = DownloadToken::new();
+        let tracker = ProgressTracker::new(0, 1, |_| Ok(()));
+        let result = install_staged_product(
+            &options,
+            &["product.zip"],
+            "RJ000001",
+            dir.path(),
+            &token,
+            &tracker,
+        );
+
+        assert!(matches!(result, Err(Error::ArchivePasswordRequired { .. })));
+        assert_eq!(read_all(&live_path), live_files);
+        assert!(staging_path.join("product.zip").exists());
+    }
+
+    #[test]
+    fn next_owner_is_tried_when_the_account_cannot_sign_in() {
+        let token = DownloadToken::new();
+        let mut tried_account_ids = Vec::new();
+        let result = block_on(download_with_fallback(
+            "RJ000001",
+            &token,
+            1,
+            vec![2, 3],
+            |account_id| {
+                tried_account_ids.push(account_id);
+
+                async move {
+                    match account_id {
+                        1 => Err(Error::DLsiteNotAuthenticated),
+                        _ => Ok(PathBuf::from("RJ000001")),
+                    }
+                }
+            },
+        ));
+
+        assert_eq!(result.unwrap(), PathBuf::from("RJ000001"));
+        assert_eq!(tried_account_ids, vec![1, 2]);
+    }
+
+    #[test]
+    fn rejected_files_are_not_tried_with_other_owners() {
+        let token = DownloadToken::new();
+        let mut tried_account_ids = Vec::new();
+        let result = block_on(download_with_fallback(
+            "RJ000001",
+            &token,
+            1,
+            vec![2],
+            |account_id| {
+                tried_account_ids.push(account_id);
+
+                async move {
+                    Err(Error::ProductDownloadRejected {
+                        file_name: "RJ000001.zip".to_owned(),
+                        reason: "the DLsite responded with 404 Not Found".to_owned(),
+                    })
+                }
+            },
+        ));
+
+        assert_eq!(result.unwrap_err().kind(), ApplicationErrorKind::Rejected);
+        assert_eq!(tried_account_ids, vec![1]);
+    }
+}
@@ -0,0 +1,194 @@
+use super::DownloadToken;
+use crate::application_error::Result;
+use parking_lot::Mutex;
+use serde::Serialize;
+use std::{
+    io::{Error as IOError, ErrorKind, Read, Result as IOResult, Seek, SeekFrom},
+    sync::atomic::{AtomicU64, Ordering},
+    time::{Duration, Instant},
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub enum DownloadPhase {
+    Downloading,
+    Extracting,
+    CleaningUp,
+}
+
+#[derive(Debug, Clone)]
+pub struct DownloadProgress {
+    pub phase: DownloadPhase,
+    pub progress: u64,
+    pub total_progress: u64,
+    pub transferred: u64,
+    pub file_index: usize,
+    pub file_count: usize,
+    pub file_name: String,
+}
+
+#[derive(Debug)]
+struct ProgressState {
+    phase: DownloadPhase,
+    total_progress: u64,
+    file_index: usize,
+    file_name: String,
+    last_report_time: Instant,
+    last_reported_transferred: u64,
+}
+
+/// Aggregates the progress of every file, segment and phase of a product into a single report.
+pub struct ProgressTracker<'a> {
+    progress: AtomicU64,
+    transferred: AtomicU64,
+    file_count: usize,
+    state: Mutex<ProgressState>,
+    on_progress: Box<dyn Fn(DownloadProgress) -> Result<()> + Send + Sync + 'a>,
+}
+
+impl<'a> ProgressTracker<'a> {
+    pub fn new(
+        total_progress: u64,
+        file_count: usize,
+        on_progress: impl Fn(DownloadProgress) -> Result<()> + Send + Sync + 'a,
+    ) -> Self {
+        Self {
+            progress: AtomicU64::new(0),
+            transferred: AtomicU64::new(0),
+            file_count,
+            state: Mutex::new(ProgressState {
+                phase: DownloadPhase::Downloading,
+                total_progress,
+                file_index: 0,
+                file_name: String::new(),
+                last_report_time: Instant::now(),
+                last_reported_transferred: 0,
+            }),
+            on_progress: Box::new(on_progress),
+        }
+    }
+
+    pub fn begin_phase(&self, phase: DownloadPhase, total_progress: u64) -> Result<()> {
+        {
+            let mut state = self.state.lock();
+            state.phase = phase;
+            state.total_progress = total_progress;
+        }
+
+        self.progress.store(0, Ordering::Relaxed);
+        self.report()
+    }
+
+    pub fn begin_file(&self, file_index: usize, file_name: impl Into<String>) -> Result<()> {
+        {
+            let mut state = self.state.lock();
+            state.file_index = file_index;
+            state.file_name = file_name.into();
+        }
+
+        self.report()
+    }
+
+    /// Names a file that is not one of the product files, such as an archive found in another.
+    pub fn begin_inner_file(&self, file_name: impl Into<String>) -> Result<()> {
+        self.state.lock().file_name = file_name.into();
+        self.report()
+    }
+
+    pub fn report(&self) -> Result<()> {
+        let progress = {
+            let mut state = self.state.lock();
+            let transferred = self.transferred.load(Ordering::Relaxed);
+            state.last_report_time = Instant::now();
+            state.last_reported_transferred = transferred;
+
+            DownloadProgress {
+                phase: state.phase,
+                progress: self.progress.load(Ordering::Relaxed),
+                total_progress: state.total_progress,
+                transferred,
+                file_index: state.file_index,
+                file_count: self.file_count,
+                file_name: state.file_name.clone(),
+            }
+        };
+
+        (self.on_progress)(progress)
+    }
+
+    pub fn finish(&self) -> Result<()> {
+        self.progress
+            .store(self.state.lock().total_progress, Ordering::Relaxed);
+        self.report()
+    }
+
+    pub fn skip(&self, amount: u64) {
+        self.progress.fetch_add(amount, Ordering::Relaxed);
+    }
+
+    pub fn rewind(&self, amount: u64) {
+        self.progress.fetch_sub(amount, Ordering::Relaxed);
+    }
+
+    pub fn transfer(&self, amount: u64) -> Result<()> {
+        self.transferred.fetch_add(amount, Ordering::Relaxed);
+        self.advance(amount)
+    }
+
+    pub fn advance(&self, amount: u64) -> Result<()> {
+        self.progress.fetch_add(amount, Ordering::Relaxed);
+
+        if Instant::now() - self.state.lock().last_report_time < Duration::from_secs(1) {
+            return Ok(());
+        }
+
+        self.report()
+    }
+}
+
+// Reports are throttled, so the bytes transferred since the last one would be lost on downloads that
+// fail or get cancelled in between.
+impl<'a> Drop for ProgressTracker<'a> {
+    fn drop(&mut self) {
+        if self.transferred.load(Ordering::Relaxed) != self.state.lock().last_reported_transferred {
+            self.report().ok();
+        }
+    }
+}
+
+/// Counts the bytes an archive reader consumes, for extractors that do not report progress themselves.
+pub struct ProgressReader<'a, R> {
+    reader: R,
+    token: &'a DownloadToken,
+    tracker: &'a ProgressTracker<'a>,
+}
+
+impl<'a, R> ProgressReader<'a, R> {
+    pub fn new(reader: R, token: &'a DownloadToken, tracker: &'a ProgressTracker<'a>) -> Self {
+        Self {
+            reader,
+            token,
+            tracker,
+        }
+    }
+}
+
+impl<'a, R: Read> Read for ProgressReader<'a, R> {
+    fn read(&mut self, buf: &mut [u8]) -> IOResult<usize> {
+        // The extractor only sees an I/O error; the caller checks the token again to tell why.
+        self.token
+            .check()
+            .map_err(|err| IOError::new(ErrorKind::Other, err.to_string()))?;
+
+        let read = self.reader.read(buf)?;
+        self.tracker
+            .advance(read as u64)
+            .map_err(|err| IOError::new(ErrorKind::Other, err.to_string()))?;
+        Ok(read)
+    }
+}
+
+impl<'a, R: Seek> Seek for ProgressReader<'a, R> {
+    fn seek(&mut self, pos: SeekFrom) -> IOResult<u64> {
+        self.reader.seek(pos)
+    }
+}
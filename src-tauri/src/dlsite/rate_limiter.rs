@@ -0,0 +1,66 @@
+use parking_lot::Mutex;
+use std::time::{Duration, Instant};
+use tokio::time::sleep;
+
+#[derive(Debug)]
+pub struct RateLimiter {
+    state: Mutex<RateLimiterState>,
+}
+
+#[derive(Debug)]
+struct RateLimiterState {
+    limit: Option<u64>,
+    available: f64,
+    last_refill: Instant,
+}
+
+impl RateLimiter {
+    pub fn new(limit: Option<u64>) -> Self {
+        Self {
+            state: Mutex::new(RateLimiterState {
+                limit: limit.filter(|&limit| limit != 0),
+                available: 0f64,
+                last_refill: Instant::now(),
+            }),
+        }
+    }
+
+    pub fn set_limit(&self, limit: Option<u64>) {
+        let limit = limit.filter(|&limit| limit != 0);
+        let mut state = self.state.lock();
+
+        if state.limit == limit {
+            return;
+        }
+
+        state.limit = limit;
+        state.available = 0f64;
+        state.last_refill = Instant::now();
+    }
+
+    /// Takes `amount` bytes out of the budget shared by every download, waiting until it refills.
+    pub async fn acquire(&self, amount: u64) {
+        let wait = {
+            let mut state = self.state.lock();
+            let limit = match state.limit {
+                Some(limit) => limit as f64,
+                None => return,
+            };
+            let now = Instant::now();
+
+            // Only one second worth of budget is kept so that idle periods do not turn into bursts.
+            state.available =
+                (state.available + (now - state.last_refill).as_secs_f64() * limit).min(limit);
+            state.last_refill = now;
+            state.available -= amount as f64;
+
+            if 0f64 <= state.available {
+                return;
+            }
+
+            Duration::from_secs_f64(-state.available / limit)
+        };
+
+        sleep(wait).await;
+    }
+}
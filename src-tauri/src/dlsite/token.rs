@@ -0,0 +1,54 @@
+use crate::application_error::{Error, Result};
+use std::{
+    future::Future,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+};
+use tokio_util::sync::CancellationToken;
+
+#[derive(Debug, Clone, Default)]
+pub struct DownloadToken {
+    token: CancellationToken,
+    paused: Arc<AtomicBool>,
+}
+
+impl DownloadToken {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn cancel(&self) {
+        self.token.cancel();
+    }
+
+    pub fn pause(&self) {
+        // Set the flag first so that the stop is reported as a pause, not as a cancel.
+        self.paused.store(true, Ordering::SeqCst);
+        self.token.cancel();
+    }
+
+    pub fn check(&self) -> Result<()> {
+        if self.token.is_cancelled() {
+            Err(self.stop_error())
+        } else {
+            Ok(())
+        }
+    }
+
+    pub async fn run<F: Future>(&self, future: F) -> Result<F::Output> {
+        tokio::select! {
+            output = future => Ok(output),
+            _ = self.token.cancelled() => Err(self.stop_error()),
+        }
+    }
+
+    fn stop_error(&self) -> Error {
+        if self.paused.load(Ordering::SeqCst) {
+            Error::ProductDownloadPaused
+        } else {
+            Error::ProductDownloadCancelled
+        }
+    }
+}
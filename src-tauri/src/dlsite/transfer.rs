@@ -0,0 +1,399 @@
+use super::{progress::ProgressTracker, DownloadOptions, DownloadToken};
+use crate::application_error::{Error, Result};
+use futures::future::try_join_all;
+use reqwest::{header::CONTENT_TYPE, Client, Response, StatusCode};
+use std::{
+    fs::{metadata, remove_file, rename, File, OpenOptions},
+    io::{copy, BufWriter, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+    time::Duration,
+};
+use tokio::time::sleep;
+
+static MAX_RETRY_BACKOFF: Duration = Duration::from_secs(60);
+static MIN_SEGMENT_SIZE: u64 = 8 * 1024 * 1024;
+
+enum DownloadFailure {
+    Transient(String),
+    Fatal(String),
+    NotAuthenticated,
+}
+
+pub struct RemoteFile<'a> {
+    pub url: &'a str,
+    pub name: &'a str,
+    pub path: &'a Path,
+    pub size: u64,
+}
+
+struct FileRange {
+    path: PathBuf,
+    offset: u64,
+    size: u64,
+}
+
+struct RangeWriter {
+    writer: BufWriter<File>,
+    written: u64,
+}
+
+pub async fn download_file(
+    client: &Client,
+    options: &DownloadOptions,
+    token: &DownloadToken,
+    file: &RemoteFile<'_>,
+    tracker: &ProgressTracker<'_>,
+) -> Result<()> {
+    if let Ok(metadata) = metadata(file.path) {
+        if metadata.len() == file.size {
+            tracker.skip(file.size);
+            return Ok(());
+        }
+    }
+
+    let segments = get_segment_count(options.segments, file.size);
+
+    if 1 < segments {
+        match download_segmented(client, options, token, file, segments, tracker).await {
+            // Servers that ignore ranges can only be downloaded through a single connection.
+            Err(Error::ProductDownloadRangeUnsupported) => {}
+            result => return result,
+        }
+    } else {
+        remove_segments(file.path)?;
+    }
+
+    let range = FileRange {
+        path: file.path.to_owned(),
+        offset: 0,
+        size: file.size,
+    };
+    download_range(client, options, token, file, &range, tracker).await
+}
+
+async fn download_segmented(
+    client: &Client,
+    options: &DownloadOptions,
+    token: &DownloadToken,
+    file: &RemoteFile<'_>,
+    segments: u64,
+    tracker: &ProgressTracker<'_>,
+) -> Result<()> {
+    let segment_size = (file.size + segments - 1) / segments;
+    let ranges = (0..segments)
+        .map(|index| {
+            let offset = index * segment_size;
+            FileRange {
+                path: get_segment_path(file.path, index),
+                offset,
+                size: segment_size.min(file.size - offset),
+            }
+        })
+        .collect::<Vec<_>>();
+
+    // A partial file left by a single-connection download starts at the same offset as the first
+    // segment, so it is resumed as that segment.
+    if let Ok(metadata) = metadata(file.path) {
+        if !ranges[0].path.exists() {
+            rename(file.path, &ranges[0].path)
+                .map_err(|err| Error::ProductFileWriteError { io_error: err })?;
+
+            if ranges[0].size < metadata.len() {
+                OpenOptions::new()
+                    .write(true)
+                    .open(&ranges[0].path)
+                    .and_then(|output| output.set_len(ranges[0].size))
+                    .map_err(|err| Error::ProductFileWriteError { io_error: err })?;
+            }
+        }
+    }
+
+    let result = try_join_all(
+        ranges
+            .iter()
+            .map(|range| download_range(client, options, token, file, range, tracker)),
+    )
+    .await;
+
+    if let Err(Error::ProductDownloadRangeUnsupported) = result {
+        for range in &ranges {
+            if let Ok(metadata) = metadata(&range.path) {
+                tracker.rewind(metadata.len());
+            }
+        }
+
+        remove_segments(file.path)?;
+        return Err(Error::ProductDownloadRangeUnsupported);
+    }
+
+    result?;
+    token.check()?;
+
+    let mut writer = BufWriter::with_capacity(
+        1024 * 1024,
+        File::create(file.path).map_err(|err| Error::ProductFileCreationError { io_error: err })?,
+    );
+
+    for range in &ranges {
+        let mut reader = File::open(&range.path)
+            .map_err(|err| Error::ProductFileCreationError { io_error: err })?;
+        copy(&mut reader, &mut writer)
+            .map_err(|err| Error::ProductFileWriteError { io_error: err })?;
+    }
+
+    writer
+        .flush()
+        .map_err(|err| Error::ProductFileWriteError { io_error: err })?;
+    remove_segments(file.path)
+}
+
+async fn download_range(
+    client: &Client,
+    options: &DownloadOptions,
+    token: &DownloadToken,
+    file: &RemoteFile<'_>,
+    range: &FileRange,
+    tracker: &ProgressTracker<'_>,
+) -> Result<()> {
+    // Partial files left by a previous attempt are resumed instead of being downloaded again.
+    let written = match metadata(&range.path) {
+        Ok(metadata) if metadata.len() == range.size => {
+            tracker.skip(range.size);
+            return Ok(());
+        }
+        Ok(metadata) if metadata.len() < range.size => metadata.len(),
+        _ => 0,
+    };
+
+    let output = OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(&range.path)
+        .map_err(|err| Error::ProductFileCreationError { io_error: err })?;
+    output
+        .set_len(written)
+        .map_err(|err| Error::ProductFileWriteError { io_error: err })?;
+    let mut output = RangeWriter {
+        writer: BufWriter::with_capacity(1024 * 1024, output),
+        written,
+    };
+    output
+        .writer
+        .seek(SeekFrom::Start(written))
+        .map_err(|err| Error::ProductFileWriteError { io_error: err })?;
+
+    tracker.skip(written);
+
+    let result = transfer_range(client, options, token, file, range, &mut output, tracker).await;
+    // Flushed on failures too, so that the length on disk matches what the tracker counted.
+    let flushed = output
+        .writer
+        .flush()
+        .map_err(|err| Error::ProductFileWriteError { io_error: err });
+
+    result.and(flushed)
+}
+
+async fn transfer_range(
+    client: &Client,
+    options: &DownloadOptions,
+    token: &DownloadToken,
+    file: &RemoteFile<'_>,
+    range: &FileRange,
+    output: &mut RangeWriter,
+    tracker: &ProgressTracker<'_>,
+) -> Result<()> {
+    let is_segment = range.size != file.size;
+    let mut retries = 0;
+    let mut last_failure = String::new();
+
+    'req: loop {
+        if retries != 0 {
+            if options.max_retries < retries {
+                return Err(Error::ProductDownloadRetryExhausted {
+                    file_name: file.name.to_owned(),
+                    retries: options.max_retries,
+                    reason: last_failure,
+                });
+            }
+
+            token.run(sleep(get_retry_backoff(retries))).await?;
+        }
+
+        let header = if is_segment {
+            format!(
+                "bytes={}-{}",
+                range.offset + output.written,
+                range.offset + range.size - 1
+            )
+        } else {
+            format!("bytes={}-", output.written)
+        };
+        let mut response = match token
+            .run(client.get(file.url).header("range", header).send())
+            .await?
+        {
+            Ok(response) => response,
+            Err(err) => {
+                retries += 1;
+                last_failure = err.to_string();
+                continue;
+            }
+        };
+
+        match check_download_response(&response) {
+            Ok(()) => {}
+            Err(DownloadFailure::Transient(reason)) => {
+                retries += 1;
+                last_failure = reason;
+                continue;
+            }
+            Err(DownloadFailure::Fatal(reason)) => {
+                return Err(Error::ProductDownloadRejected {
+                    file_name: file.name.to_owned(),
+                    reason,
+                });
+            }
+            Err(DownloadFailure::NotAuthenticated) => {
+                return Err(Error::DLsiteNotAuthenticated);
+            }
+        }
+
+        if response.status() != StatusCode::PARTIAL_CONTENT {
+            if is_segment {
+                return Err(Error::ProductDownloadRangeUnsupported);
+            }
+
+            // The server ignored the range header, so the file has to be written from the start.
+            if output.written != 0 {
+                tracker.rewind(output.written);
+                output.written = 0;
+                output
+                    .writer
+                    .seek(SeekFrom::Start(0))
+                    .map_err(|err| Error::ProductFileWriteError { io_error: err })?;
+                output
+                    .writer
+                    .get_ref()
+                    .set_len(0)
+                    .map_err(|err| Error::ProductFileWriteError { io_error: err })?;
+            }
+        }
+
+        while let Some(chunk) = match token.run(response.chunk()).await? {
+            Ok(chunk) => chunk,
+            Err(err) => {
+                retries += 1;
+                last_failure = err.to_string();
+                continue 'req;
+            }
+        } {
+            token
+                .run(options.rate_limiter.acquire(chunk.len() as u64))
+                .await?;
+            output
+                .writer
+                .write_all(&chunk)
+                .map_err(|err| Error::ProductFileWriteError { io_error: err })?;
+            output.written += chunk.len() as u64;
+            retries = 0;
+            tracker.transfer(chunk.len() as u64)?;
+        }
+
+        return Ok(());
+    }
+}
+
+/// Returns how much of the file is already on disk, counting the segments of an interrupted
+/// segmented download.
+pub fn get_downloaded_size(path: &Path, size: u64) -> u64 {
+    let mut downloaded = metadata(path).map(|metadata| metadata.len()).unwrap_or(0);
+    let mut index = 0;
+
+    while let Ok(metadata) = metadata(get_segment_path(path, index)) {
+        downloaded += metadata.len();
+        index += 1;
+    }
+
+    downloaded.min(size)
+}
+
+fn get_segment_count(segments: usize, size: u64) -> u64 {
+    (segments as u64).min(size / MIN_SEGMENT_SIZE).max(1)
+}
+
+fn get_segment_path(path: &Path, index: u64) -> PathBuf {
+    let mut file_name = path.file_name().unwrap_or_default().to_owned();
+    file_name.push(format!(".seg{}", index));
+    path.with_file_name(file_name)
+}
+
+fn remove_segments(path: &Path) -> Result<()> {
+    let mut index = 0;
+
+    loop {
+        let segment_path = get_segment_path(path, index);
+
+        if !segment_path.exists() {
+            return Ok(());
+        }
+
+        remove_file(&segment_path).map_err(|err| Error::ProductFileWriteError { io_error: err })?;
+        index += 1;
+    }
+}
+
+fn check_download_response(response: &Response) -> std::result::Result<(), DownloadFailure> {
+    let status = response.status();
+
+    match status {
+        StatusCode::UNAUTHORIZED => {
+            return Err(DownloadFailure::NotAuthenticated);
+        }
+        StatusCode::FORBIDDEN | StatusCode::NOT_FOUND => {
+            return Err(DownloadFailure::Fatal(format!(
+                "the DLsite responded with {}",
+                status
+            )));
+        }
+        StatusCode::REQUEST_TIMEOUT | StatusCode::TOO_MANY_REQUESTS => {
+            return Err(DownloadFailure::Transient(format!(
+                "the DLsite responded with {}",
+                status
+            )));
+        }
+        _ if status.is_server_error() => {
+            return Err(DownloadFailure::Transient(format!(
+                "the DLsite responded with {}",
+                status
+            )));
+        }
+        _ if !status.is_success() => {
+            return Err(DownloadFailure::Fatal(format!(
+                "the DLsite responded with {}",
+                status
+            )));
+        }
+        _ => {}
+    }
+
+    // The DLsite serves its login page with 200 status when the session has been expired, so the
+    // account has to sign in again.
+    let is_html = response
+        .headers()
+        .get(CONTENT_TYPE)
+        .and_then(|content_type| content_type.to_str().ok())
+        .map(|content_type| content_type.starts_with("text/html"))
+        .unwrap_or(false);
+
+    if is_html {
+        return Err(DownloadFailure::NotAuthenticated);
+    }
+
+    Ok(())
+}
+
+fn get_retry_backoff(retries: u32) -> Duration {
+    Duration::from_secs(1 << (retries - 1).min(6)).min(MAX_RETRY_BACKOFF)
+}
@@ -0,0 +1,649 @@
+use crate::{
+    application::use_application,
+    application_error::{ApplicationErrorKind, Error, Result},
+    command::get_product_download_path,
+    dlsite::{
+        commit_staged_product, download_product, extract_downloaded_product,
+        extractor::{ArchiveDisposal, ExtractOptions, KEPT_ARCHIVE_DIR_NAME},
+        get_available_space, get_staging_path, remove_staged_product, DownloadOptions,
+        DownloadPhase, DownloadProgress, DownloadToken, RateLimiter,
+    },
+    storage::{
+        archive_password::ArchivePassword,
+        download_history::{DownloadHistory, DownloadOutcome},
+        download_queue::QueuedDownload,
+        product::{Product, ProductDownload},
+        setting::{KeepArchives, Setting},
+    },
+    window::{MainWindow, WindowInfoProvider},
+};
+use chrono::Local;
+use log::error;
+use parking_lot::Mutex;
+use serde::Serialize;
+use std::{
+    collections::HashMap,
+    iter::once,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+use tauri::{
+    async_runtime::{spawn, spawn_blocking},
+    Manager,
+};
+use tokio::time::sleep;
+
+static SCHEDULE_CHECK_INTERVAL: Duration = Duration::from_secs(30);
+static SPEED_SAMPLE_INTERVAL: Duration = Duration::from_millis(500);
+static SPEED_SMOOTHING: f64 = 0.3;
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ProductDownloadProgressEvent<'s> {
+    pub product_id: &'s str,
+    pub phase: DownloadPhase,
+    pub progress: usize,
+    pub bytes_done: u64,
+    pub bytes_total: u64,
+    pub file_index: usize,
+    pub file_count: usize,
+    pub file_name: &'s str,
+    pub speed: f64,
+    pub eta: Option<u64>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ProductDownloadEndEvent<'s> {
+    pub product_id: &'s str,
+    pub download: Option<ProductDownload>,
+    pub error: Option<ProductDownloadFailure>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ProductDownloadFailure {
+    pub kind: ApplicationErrorKind,
+    pub message: String,
+}
+
+impl From<&Error> for ProductDownloadFailure {
+    fn from(err: &Error) -> Self {
+        Self {
+            kind: err.kind(),
+            message: err.to_string(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ProductDownloadWaitingEvent<'s> {
+    pub product_id: &'s str,
+    pub required: u64,
+    pub available: u64,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct DownloadQueueStatus {
+    pub queue: Vec<QueuedDownload>,
+    pub running: Vec<String>,
+    pub waiting_for_space: Vec<String>,
+}
+
+struct SpeedEstimator {
+    phase: DownloadPhase,
+    last_sample: Option<(Instant, u64)>,
+    speed: f64,
+}
+
+impl SpeedEstimator {
+    fn new() -> Self {
+        Self {
+            phase: DownloadPhase::Downloading,
+            last_sample: None,
+            speed: 0f64,
+        }
+    }
+
+    /// Returns the exponentially smoothed speed in bytes per second.
+    fn update(&mut self, progress: &DownloadProgress) -> f64 {
+        // Resumed bytes are not transferred, so they must not count towards the download speed.
+        let done = match progress.phase {
+            DownloadPhase::Downloading => progress.transferred,
+            _ => progress.progress,
+        };
+        let now = Instant::now();
+
+        if self.phase != progress.phase {
+            self.phase = progress.phase;
+            self.last_sample = None;
+            self.speed = 0f64;
+        }
+
+        if let Some((last_time, last_done)) = self.last_sample {
+            let elapsed = now - last_time;
+
+            if elapsed < SPEED_SAMPLE_INTERVAL {
+                return self.speed;
+            }
+
+            let speed = done.saturating_sub(last_done) as f64 / elapsed.as_secs_f64();
+
+            self.speed = if self.speed == 0f64 {
+                speed
+            } else {
+                SPEED_SMOOTHING * speed + (1f64 - SPEED_SMOOTHING) * self.speed
+            };
+        }
+
+        self.last_sample = Some((now, done));
+        self.speed
+    }
+}
+
+pub struct Downloader {
+    running: Mutex<HashMap<String, DownloadToken>>,
+    // Required bytes of the queued downloads held back until the download volume has room.
+    waiting_for_space: Mutex<HashMap<String, u64>>,
+    rate_limiter: Arc<RateLimiter>,
+}
+
+impl Downloader {
+    pub fn new() -> Self {
+        Self {
+            running: Mutex::new(HashMap::new()),
+            waiting_for_space: Mutex::new(HashMap::new()),
+            rate_limiter: Arc::new(RateLimiter::new(None)),
+        }
+    }
+
+    /// Retries the queue periodically so that held downloads start once a schedule window opens.
+    pub fn start_scheduler(&self) {
+        spawn(async {
+            loop {
+                sleep(SCHEDULE_CHECK_INTERVAL).await;
+
+                if let Err(err) = use_application().downloader().pump() {
+                    error!("cannot start scheduled download: {}", err);
+                }
+
+                notify_download_queue_changed().ok();
+            }
+        });
+    }
+
+    pub fn status(&self) -> Result<DownloadQueueStatus> {
+        Ok(DownloadQueueStatus {
+            queue: QueuedDownload::list_all()?,
+            running: self.running.lock().keys().cloned().collect(),
+            waiting_for_space: self.waiting_for_space.lock().keys().cloned().collect(),
+        })
+    }
+
+    pub fn pump(&self) -> Result<()> {
+        let setting = Setting::get()?;
+        let max_concurrent_downloads = setting.max_concurrent_downloads();
+
+        self.rate_limiter.set_limit(setting.download_rate_limit());
+
+        // Running downloads are left alone when a window closes; only new ones are held back.
+        if !setting.is_download_window_open(Local::now().time()) {
+            return Ok(());
+        }
+
+        let mut running = self.running.lock();
+
+        if max_concurrent_downloads <= running.len() {
+            return Ok(());
+        }
+
+        let mut waiting_for_space = self.waiting_for_space.lock();
+        let mut available_space = None;
+
+        for queued in QueuedDownload::list_all()? {
+            if max_concurrent_downloads <= running.len() {
+                break;
+            }
+
+            if queued.paused || running.contains_key(&queued.product_id) {
+                continue;
+            }
+
+            if let Some(&required) = waiting_for_space.get(&queued.product_id) {
+                let available = match available_space {
+                    Some(available) => available,
+                    None => {
+                        let path = get_product_download_path(use_application().app_handle())?;
+                        *available_space.insert(get_available_space(path)?)
+                    }
+                };
+
+                if available < required {
+                    continue;
+                }
+
+                // The space is only taken once the download writes to disk, so it is reserved here to
+                // keep the next waiting download from counting on it as well.
+                available_space = Some(available - required);
+                waiting_for_space.remove(&queued.product_id);
+            }
+
+            let token = DownloadToken::new();
+            running.insert(queued.product_id.clone(), token.clone());
+            spawn(run_download(queued, token));
+        }
+
+        Ok(())
+    }
+
+    pub fn cancel(&self, product_id: impl AsRef<str>) -> Result<()> {
+        QueuedDownload::remove_one(product_id.as_ref())?;
+        self.waiting_for_space.lock().remove(product_id.as_ref());
+
+        // Running downloads clean up after themselves once they observe the cancellation.
+        if let Some(token) = self.running.lock().get(product_id.as_ref()) {
+            token.cancel();
+            return Ok(());
+        }
+
+        remove_partial_download(product_id.as_ref())?;
+        emit_download_event("download-cancelled", product_id.as_ref())?;
+        notify_download_queue_changed()?;
+        Ok(())
+    }
+
+    pub fn pause(&self, product_id: impl AsRef<str>) -> Result<()> {
+        if let Some(token) = self.running.lock().get(product_id.as_ref()) {
+            token.pause();
+            return Ok(());
+        }
+
+        if QueuedDownload::get_one(product_id.as_ref())?.is_some() {
+            QueuedDownload::update_one_paused(product_id.as_ref(), true)?;
+            emit_download_event("download-paused", product_id.as_ref())?;
+            notify_download_queue_changed()?;
+        }
+
+        Ok(())
+    }
+
+    pub fn resume(&self, product_id: impl AsRef<str>) -> Result<()> {
+        QueuedDownload::update_one_paused(product_id.as_ref(), false)?;
+        self.pump()?;
+        notify_download_queue_changed()?;
+        Ok(())
+    }
+
+    /// Extracts the archives left in downloaded products one after another. Products that are being
+    /// downloaded or extracted at the moment are skipped, and so are the archives kept after an
+    /// earlier extraction unless `use_kept_archives` is set.
+    pub fn extract(&self, product_ids: Vec<String>, use_kept_archives: bool) {
+        spawn(async move {
+            for product_id in product_ids {
+                let token = DownloadToken::new();
+
+                {
+                    let mut running = use_application().downloader().running.lock();
+
+                    if running.contains_key(&product_id) {
+                        continue;
+                    }
+
+                    running.insert(product_id.clone(), token.clone());
+                }
+
+                // Extraction blocks for as long as the archives take, so it is kept off the
+                // runtime that runs the downloads and the scheduler.
+                let result = spawn_blocking({
+                    let product_id = product_id.clone();
+                    move || extract(&product_id, use_kept_archives, &token)
+                })
+                .await
+                .map_err(Error::from)
+                .and_then(|result| result);
+
+                if let Err(err) = result {
+                    error!("extraction of {} failed: {}", product_id, err);
+                }
+
+                use_application().downloader().finish(&product_id);
+                notify_download_queue_changed().ok();
+            }
+
+            if let Err(err) = use_application().downloader().pump() {
+                error!("cannot start next download: {}", err);
+            }
+        });
+    }
+
+    fn finish(&self, product_id: impl AsRef<str>) {
+        self.running.lock().remove(product_id.as_ref());
+    }
+
+    fn wait_for_space(&self, product_id: impl Into<String>, required: u64) {
+        self.waiting_for_space
+            .lock()
+            .insert(product_id.into(), required);
+    }
+}
+
+pub fn notify_download_queue_changed() -> Result<()> {
+    if let Some(window) = use_application()
+        .app_handle()
+        .get_window(&MainWindow.label())
+    {
+        window.emit(
+            "download-queue-changed",
+            use_application().downloader().status()?,
+        )?;
+    }
+
+    Ok(())
+}
+
+fn emit_download_event(event: &str, product_id: impl AsRef<str>) -> Result<()> {
+    if let Some(window) = use_application()
+        .app_handle()
+        .get_window(&MainWindow.label())
+    {
+        window.emit(event, product_id.as_ref())?;
+    }
+
+    Ok(())
+}
+
+fn emit_download_progress(
+    product_id: impl AsRef<str>,
+    progress: &DownloadProgress,
+    speed: f64,
+) -> Result<()> {
+    let remaining = progress.total_progress.saturating_sub(progress.progress);
+
+    if let Some(window) = use_application()
+        .app_handle()
+        .get_window(&MainWindow.label())
+    {
+        window.emit(
+            "download-progress",
+            ProductDownloadProgressEvent {
+                product_id: product_id.as_ref(),
+                phase: progress.phase,
+                progress: if progress.total_progress == 0 {
+                    100
+                } else {
+                    (progress.progress.min(progress.total_progress) as f64
+                        / progress.total_progress as f64
+                        * 100f64)
+                        .round() as usize
+                },
+                bytes_done: progress.progress,
+                bytes_total: progress.total_progress,
+                file_index: progress.file_index,
+                file_count: progress.file_count,
+                file_name: &progress.file_name,
+                speed,
+                eta: if 0f64 < speed {
+                    Some((remaining as f64 / speed).ceil() as u64)
+                } else {
+                    None
+                },
+            },
+        )?;
+    }
+
+    Ok(())
+}
+
+fn remove_partial_download(product_id: impl AsRef<str>) -> Result<()> {
+    let path = get_product_download_path(use_application().app_handle())?;
+    remove_staged_product(product_id.as_ref(), &path)
+}
+
+async fn run_download(queued: QueuedDownload, token: DownloadToken) {
+    let product_id = queued.product_id.clone();
+
+    match download(queued, token).await {
+        Err(Error::ProductDownloadPaused) => {
+            QueuedDownload::update_one_paused(&product_id, true).ok();
+            emit_download_event("download-paused", &product_id).ok();
+        }
+        Err(Error::ProductDownloadCancelled) => {
+            QueuedDownload::remove_one(&product_id).ok();
+            remove_partial_download(&product_id).ok();
+            emit_download_event("download-cancelled", &product_id).ok();
+        }
+        // The download stays queued and is retried by the scheduler once enough space is freed.
+        Err(Error::InsufficientDiskSpace {
+            required,
+            available,
+        }) => {
+            use_application()
+                .downloader()
+                .wait_for_space(&product_id, required);
+
+            if let Some(window) = use_application()
+                .app_handle()
+                .get_window(&MainWindow.label())
+            {
+                window
+                    .emit(
+                        "download-waiting-for-space",
+                        ProductDownloadWaitingEvent {
+                            product_id: &product_id,
+                            required,
+                            available,
+                        },
+                    )
+                    .ok();
+            }
+        }
+        result => {
+            if let Err(err) = result {
+                error!("download of {} failed: {}", product_id, err);
+            }
+
+            QueuedDownload::remove_one(&product_id).ok();
+        }
+    }
+
+    use_application().downloader().finish(&product_id);
+
+    if let Err(err) = use_application().downloader().pump() {
+        error!("cannot start next download: {}", err);
+    }
+
+    notify_download_queue_changed().ok();
+}
+
+pub fn get_extract_options(
+    setting: &Setting,
+    product_id: impl AsRef<str>,
+) -> Result<ExtractOptions> {
+    // The password entered for the product goes first, as it is the most likely to match.
+    let passwords = ArchivePassword::get_one(product_id.as_ref())?
+        .map(|password| password.password)
+        .into_iter()
+        .chain(setting.archive_passwords.iter().cloned())
+        .collect();
+    let product_type = Product::get_one_type(product_id.as_ref())?;
+    let archive_disposal = match setting.keep_archives(product_type.as_ref()) {
+        KeepArchives::Never => ArchiveDisposal::Remove,
+        KeepArchives::ProductFolder => ArchiveDisposal::Keep,
+        KeepArchives::ArchiveRoot => ArchiveDisposal::MoveTo(
+            match &setting.archive_root_dir {
+                Some(path) => path.clone(),
+                None => get_product_download_path(use_application().app_handle())?
+                    .join(KEPT_ARCHIVE_DIR_NAME),
+            }
+            .join(product_id.as_ref()),
+        ),
+    };
+
+    Ok(ExtractOptions {
+        zip_filename_encoding: setting.zip_filename_encoding,
+        nested_depth: setting.nested_extraction_depth(product_type.as_ref()),
+        passwords,
+        flatten_policy: setting.flatten_policy(),
+        flatten_names: once(product_id.as_ref().to_owned())
+            .chain(Product::get_one_titles(product_id.as_ref())?)
+            .collect(),
+        archive_disposal,
+    })
+}
+
+fn extract(product_id: &str, use_kept_archives: bool, token: &DownloadToken) -> Result<()> {
+    let download = match Product::get_one_download(product_id)? {
+        Some(download) if download.path.is_dir() => download,
+        _ => return Ok(()),
+    };
+    let options = get_extract_options(&Setting::get()?, product_id)?;
+    let base_path = get_product_download_path(use_application().app_handle())?;
+    let speed_estimator = Mutex::new(SpeedEstimator::new());
+
+    emit_download_event("download-begin", product_id)?;
+    notify_download_queue_changed()?;
+
+    let result = extract_downloaded_product(
+        &options,
+        &download.path,
+        use_kept_archives,
+        token,
+        |progress| {
+            let speed = speed_estimator.lock().update(&progress);
+            emit_download_progress(product_id, &progress, speed)
+        },
+    )
+    .and_then(|()| {
+        // Archives that waited for a password in the staging folder replace the live copy only
+        // once they are extracted.
+        if download.path != get_staging_path(product_id, &base_path) {
+            return Ok(download.clone());
+        }
+
+        commit_staged_product(product_id, &base_path)?;
+        Product::insert_download(product_id, base_path.join(product_id).to_str().unwrap())
+    });
+    let (download, error) = match result {
+        Ok(download) => (download, None),
+        Err(Error::ProductDownloadPaused) | Err(Error::ProductDownloadCancelled) => {
+            (download, None)
+        }
+        Err(err) => {
+            error!("extraction of {} failed: {}", product_id, err);
+            (download, Some(ProductDownloadFailure::from(&err)))
+        }
+    };
+
+    if let Some(window) = use_application()
+        .app_handle()
+        .get_window(&MainWindow.label())
+    {
+        window.emit(
+            "download-end",
+            ProductDownloadEndEvent {
+                product_id,
+                download: Some(download),
+                error,
+            },
+        )?;
+    }
+
+    Ok(())
+}
+
+async fn download(queued: QueuedDownload, token: DownloadToken) -> Result<()> {
+    let app_handle = use_application().app_handle();
+
+    emit_download_event("download-begin", &queued.product_id)?;
+    notify_download_queue_changed()?;
+
+    let setting = Setting::get()?;
+    let path = get_product_download_path(app_handle)?;
+    let history_id = DownloadHistory::create_one(queued.account_id, &queued.product_id)?;
+    let transferred = Arc::new(AtomicU64::new(0));
+    let speed_estimator = Mutex::new(SpeedEstimator::new());
+    let result = download_product(
+        DownloadOptions {
+            decompress: queued.decompress,
+            max_retries: setting.download_max_retries(),
+            rate_limiter: use_application().downloader().rate_limiter.clone(),
+            segments: setting.download_segments(),
+            extract: get_extract_options(&setting, &queued.product_id)?,
+        },
+        &token,
+        queued.account_id,
+        &queued.product_id,
+        &path,
+        {
+            let transferred = transferred.clone();
+            let product_id = queued.product_id.clone();
+
+            move |progress| {
+                transferred.store(progress.transferred, Ordering::Relaxed);
+
+                let speed = speed_estimator.lock().update(&progress);
+                emit_download_progress(&product_id, &progress, speed)
+            }
+        },
+    )
+    .await;
+    let transferred = transferred.load(Ordering::Relaxed);
+
+    let (download, error) = match result {
+        Ok(path) => {
+            DownloadHistory::finish_one(history_id, DownloadOutcome::Succeeded, transferred, None)?;
+            (
+                Some(Product::insert_download(
+                    &queued.product_id,
+                    path.to_str().unwrap(),
+                )?),
+                None,
+            )
+        }
+        Err(err) => {
+            let outcome = match err {
+                Error::ProductDownloadPaused => DownloadOutcome::Paused,
+                Error::ProductDownloadCancelled => DownloadOutcome::Cancelled,
+                _ => DownloadOutcome::Failed,
+            };
+
+            DownloadHistory::finish_one(history_id, outcome, transferred, Some(&err))?;
+
+            if outcome != DownloadOutcome::Failed
+                || matches!(err, Error::InsufficientDiskSpace { .. })
+            {
+                return Err(err);
+            }
+
+            error!("download of {} failed: {}", queued.product_id, err);
+
+            // The archives stay packed in the staging folder until a password is entered, leaving
+            // the previous copy of the product in place.
+            let download = if let Error::ArchivePasswordRequired { .. } = err {
+                Some(Product::insert_download(
+                    &queued.product_id,
+                    get_staging_path(&queued.product_id, &path)
+                        .to_str()
+                        .unwrap(),
+                )?)
+            } else {
+                None
+            };
+
+            (download, Some(ProductDownloadFailure::from(&err)))
+        }
+    };
+
+    if let Some(window) = app_handle.get_window(&MainWindow.label()) {
+        window.emit(
+            "download-end",
+            ProductDownloadEndEvent {
+                product_id: &queued.product_id,
+                download,
+                error,
+            },
+        )?;
+    }
+
+    Ok(())
+}
@@ -7,6 +7,7 @@ mod application;
 mod application_error;
 mod command;
 mod dlsite;
+mod downloader;
 mod menu;
 mod storage;
 mod window;
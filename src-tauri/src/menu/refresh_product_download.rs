@@ -31,6 +31,11 @@ pub async fn refresh_product_download() -> Result<()> {
                 continue;
             }
         };
+
+        // Hidden directories such as the staging directory never hold a finished download.
+        if file_name.starts_with('.') {
+            continue;
+        }
         let path = entry.path();
         let path = match path.to_str() {
             Some(path) => path,
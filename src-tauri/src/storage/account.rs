@@ -72,6 +72,11 @@ END;
         "
     }
 
+    pub fn get_columns() -> &'static [(&'static str, &'static str)] {
+        // The `last` timestamp of the purchase list as of the last complete sync.
+        &[("purchases_synced_last", "INTEGER")]
+    }
+
     pub fn list_all() -> Result<Vec<Self>> {
         Ok(use_application()
             .connection()
@@ -164,6 +169,22 @@ WHERE id = ?1
             .optional()?)
     }
 
+    pub fn get_one_purchases_synced_last(id: i64) -> Result<Option<DateTime<Utc>>> {
+        Ok(use_application()
+            .connection()
+            .prepare(
+                "
+SELECT
+    purchases_synced_last
+FROM accounts
+WHERE id = ?1
+        ",
+            )?
+            .query_row(params![id], |row| Ok(row.get("purchases_synced_last")?))
+            .optional()?
+            .flatten())
+    }
+
     pub fn get_one_cookie_json(id: i64) -> Result<Option<String>> {
         Ok(use_application()
             .connection()
@@ -246,6 +267,21 @@ WHERE id = ?1
         Ok(())
     }
 
+    pub fn update_one_purchases_synced_last(id: i64, last: DateTime<Utc>) -> Result<()> {
+        use_application()
+            .connection()
+            .prepare(
+                "
+UPDATE accounts
+SET
+    purchases_synced_last = ?2
+WHERE id = ?1
+        ",
+            )?
+            .execute(params![id, last])?;
+        Ok(())
+    }
+
     pub fn update_one_cookie_json(id: i64, cookie_json: impl AsRef<str>) -> Result<()> {
         use_application()
             .connection()
@@ -0,0 +1,72 @@
+use crate::{application::use_application, application_error::Result};
+use chrono::{DateTime, Utc};
+use rusqlite::{params, OptionalExtension, Row};
+use serde::{Deserialize, Serialize};
+
+/// Password of the archives of a single product, as entered by the user.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ArchivePassword {
+    pub product_id: String,
+    pub password: String,
+    pub updated_at: DateTime<Utc>,
+}
+
+impl<'stmt> TryFrom<&'stmt Row<'stmt>> for ArchivePassword {
+    type Error = rusqlite::Error;
+
+    fn try_from(row: &'stmt Row<'stmt>) -> std::result::Result<Self, Self::Error> {
+        Ok(Self {
+            product_id: row.get("product_id")?,
+            password: row.get("password")?,
+            updated_at: row.get("updated_at")?,
+        })
+    }
+}
+
+impl ArchivePassword {
+    // Not tied to the products table, so that a password survives the product list being rebuilt.
+    pub fn get_ddl() -> &'static str {
+        "
+CREATE TABLE IF NOT EXISTS archive_passwords (
+    id INTEGER PRIMARY KEY NOT NULL,
+    product_id TEXT NOT NULL UNIQUE,
+    password TEXT NOT NULL,
+    updated_at INTEGER NOT NULL DEFAULT CURRENT_TIMESTAMP
+);"
+    }
+
+    pub fn get_one(product_id: impl AsRef<str>) -> Result<Option<Self>> {
+        Ok(use_application()
+            .connection()
+            .prepare(
+                "
+SELECT
+    product_id,
+    password,
+    updated_at
+FROM archive_passwords
+WHERE product_id = ?1",
+            )?
+            .query_row(params![product_id.as_ref()], |row| Self::try_from(row))
+            .optional()?)
+    }
+
+    pub fn set_one(product_id: impl AsRef<str>, password: impl AsRef<str>) -> Result<()> {
+        use_application()
+            .connection()
+            .prepare(
+                "
+INSERT INTO archive_passwords (
+    product_id,
+    password
+) VALUES (
+    ?1,
+    ?2
+) ON CONFLICT (product_id) DO UPDATE SET
+    password = excluded.password,
+    updated_at = CURRENT_TIMESTAMP",
+            )?
+            .execute(params![product_id.as_ref(), password.as_ref()])?;
+        Ok(())
+    }
+}
@@ -0,0 +1,173 @@
+use crate::{
+    application::use_application,
+    application_error::{Error, Result},
+};
+use chrono::{DateTime, Utc};
+use rusqlite::{params, Row};
+use serde::{Deserialize, Serialize};
+use std::str::FromStr;
+use strum_macros::{EnumString, IntoStaticStr};
+
+#[derive(
+    EnumString, IntoStaticStr, Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize,
+)]
+pub enum DownloadOutcome {
+    InProgress,
+    Succeeded,
+    Failed,
+    Cancelled,
+    Paused,
+    Interrupted,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DownloadHistory {
+    pub id: i64,
+    pub account_id: i64,
+    pub product_id: String,
+    pub outcome: DownloadOutcome,
+    pub bytes_transferred: u64,
+    pub error_kind: Option<String>,
+    pub error_message: Option<String>,
+    pub started_at: DateTime<Utc>,
+    pub ended_at: Option<DateTime<Utc>>,
+}
+
+impl<'stmt> TryFrom<&'stmt Row<'stmt>> for DownloadHistory {
+    type Error = rusqlite::Error;
+
+    fn try_from(row: &'stmt Row<'stmt>) -> std::result::Result<Self, Self::Error> {
+        Ok(Self {
+            id: row.get("id")?,
+            account_id: row.get("account_id")?,
+            product_id: row.get("product_id")?,
+            outcome: <_>::from_str(row.get_ref("outcome")?.as_str()?).map_err(
+                |err: strum::ParseError| {
+                    rusqlite::Error::FromSqlConversionFailure(
+                        row.as_ref().column_index("outcome").unwrap(),
+                        rusqlite::types::Type::Text,
+                        Box::new(err),
+                    )
+                },
+            )?,
+            bytes_transferred: row.get("bytes_transferred")?,
+            error_kind: row.get("error_kind")?,
+            error_message: row.get("error_message")?,
+            started_at: row.get("started_at")?,
+            ended_at: row.get("ended_at")?,
+        })
+    }
+}
+
+impl DownloadHistory {
+    pub fn get_ddl() -> &'static str {
+        "
+CREATE TABLE IF NOT EXISTS download_history (
+    id INTEGER PRIMARY KEY NOT NULL,
+    account_id INTEGER NOT NULL,
+    product_id TEXT NOT NULL,
+    outcome TEXT NOT NULL,
+    bytes_transferred INTEGER NOT NULL DEFAULT 0,
+    error_kind TEXT,
+    error_message TEXT,
+    started_at INTEGER NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    ended_at INTEGER,
+
+    FOREIGN KEY(account_id) REFERENCES accounts(id) ON UPDATE CASCADE ON DELETE CASCADE
+);
+
+CREATE INDEX IF NOT EXISTS download_history_product_id ON download_history (product_id);"
+    }
+
+    pub fn list_all(product_id: impl AsRef<str>) -> Result<Vec<Self>> {
+        Ok(use_application()
+            .connection()
+            .prepare(
+                "
+SELECT
+    id,
+    account_id,
+    product_id,
+    outcome,
+    bytes_transferred,
+    error_kind,
+    error_message,
+    started_at,
+    ended_at
+FROM download_history
+WHERE product_id = ?1
+ORDER BY id DESC",
+            )?
+            .query_map(params![product_id.as_ref()], |row| Self::try_from(row))?
+            .collect::<rusqlite::Result<Vec<_>>>()?)
+    }
+
+    pub fn create_one(account_id: i64, product_id: impl AsRef<str>) -> Result<i64> {
+        Ok(use_application()
+            .connection()
+            .prepare(
+                "
+INSERT INTO download_history (
+    account_id,
+    product_id,
+    outcome
+) VALUES (
+    ?1,
+    ?2,
+    ?3
+)",
+            )?
+            .insert(params![
+                account_id,
+                product_id.as_ref(),
+                <_ as Into<&'static str>>::into(DownloadOutcome::InProgress),
+            ])?)
+    }
+
+    pub fn finish_one(
+        id: i64,
+        outcome: DownloadOutcome,
+        bytes_transferred: u64,
+        error: Option<&Error>,
+    ) -> Result<()> {
+        use_application()
+            .connection()
+            .prepare(
+                "
+UPDATE download_history
+SET
+    outcome = ?2,
+    bytes_transferred = ?3,
+    error_kind = ?4,
+    error_message = ?5,
+    ended_at = CURRENT_TIMESTAMP
+WHERE id = ?1",
+            )?
+            .execute(params![
+                id,
+                <_ as Into<&'static str>>::into(outcome),
+                bytes_transferred,
+                error.map(|err| <_ as Into<&'static str>>::into(err.kind())),
+                error.map(|err| err.to_string()),
+            ])?;
+        Ok(())
+    }
+
+    pub fn interrupt_all_in_progress() -> Result<()> {
+        use_application()
+            .connection()
+            .prepare(
+                "
+UPDATE download_history
+SET
+    outcome = ?2,
+    ended_at = CURRENT_TIMESTAMP
+WHERE outcome = ?1",
+            )?
+            .execute(params![
+                <_ as Into<&'static str>>::into(DownloadOutcome::InProgress),
+                <_ as Into<&'static str>>::into(DownloadOutcome::Interrupted),
+            ])?;
+        Ok(())
+    }
+}
@@ -0,0 +1,187 @@
+use crate::{
+    application::use_application,
+    application_error::{Error, Result},
+};
+use chrono::{DateTime, Utc};
+use rusqlite::{params, OptionalExtension, Row};
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct QueuedDownload {
+    pub id: i64,
+    pub account_id: i64,
+    pub product_id: String,
+    pub decompress: bool,
+    pub priority: i64,
+    pub order_index: i64,
+    pub paused: bool,
+    pub created_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct EnqueuedDownload {
+    pub account_id: i64,
+    pub product_id: String,
+    pub decompress: bool,
+    pub priority: i64,
+}
+
+impl<'stmt> TryFrom<&'stmt Row<'stmt>> for QueuedDownload {
+    type Error = rusqlite::Error;
+
+    fn try_from(row: &'stmt Row<'stmt>) -> std::result::Result<Self, Self::Error> {
+        Ok(Self {
+            id: row.get("id")?,
+            account_id: row.get("account_id")?,
+            product_id: row.get("product_id")?,
+            decompress: row.get("decompress")?,
+            priority: row.get("priority")?,
+            order_index: row.get("order_index")?,
+            paused: row.get("paused")?,
+            created_at: row.get("created_at")?,
+        })
+    }
+}
+
+impl QueuedDownload {
+    pub fn get_ddl() -> &'static str {
+        "
+CREATE TABLE IF NOT EXISTS download_queue (
+    id INTEGER PRIMARY KEY NOT NULL,
+    account_id INTEGER NOT NULL,
+    product_id TEXT NOT NULL UNIQUE,
+    decompress INTEGER NOT NULL DEFAULT 1,
+    priority INTEGER NOT NULL DEFAULT 0,
+    order_index INTEGER NOT NULL DEFAULT 0,
+    paused INTEGER NOT NULL DEFAULT 0,
+    created_at INTEGER NOT NULL DEFAULT CURRENT_TIMESTAMP,
+
+    FOREIGN KEY(account_id) REFERENCES accounts(id) ON UPDATE CASCADE ON DELETE CASCADE
+);"
+    }
+
+    pub fn list_all() -> Result<Vec<Self>> {
+        Ok(use_application()
+            .connection()
+            .prepare(
+                "
+SELECT
+    id,
+    account_id,
+    product_id,
+    decompress,
+    priority,
+    order_index,
+    paused,
+    created_at
+FROM download_queue
+ORDER BY priority DESC, order_index ASC, id ASC",
+            )?
+            .query_map((), |row| Self::try_from(row))?
+            .collect::<rusqlite::Result<Vec<_>>>()?)
+    }
+
+    pub fn get_one(product_id: impl AsRef<str>) -> Result<Option<Self>> {
+        Ok(use_application()
+            .connection()
+            .prepare(
+                "
+SELECT
+    id,
+    account_id,
+    product_id,
+    decompress,
+    priority,
+    order_index,
+    paused,
+    created_at
+FROM download_queue
+WHERE product_id = ?1",
+            )?
+            .query_row(params![product_id.as_ref()], |row| Self::try_from(row))
+            .optional()?)
+    }
+
+    pub fn enqueue(download: EnqueuedDownload) -> Result<Self> {
+        use_application()
+            .connection()
+            .prepare(
+                "
+INSERT INTO download_queue (
+    account_id,
+    product_id,
+    decompress,
+    priority,
+    order_index
+) VALUES (
+    ?1,
+    ?2,
+    ?3,
+    ?4,
+    (SELECT COALESCE(MAX(order_index), -1) + 1 FROM download_queue)
+) ON CONFLICT (product_id) DO UPDATE SET
+    account_id = excluded.account_id,
+    decompress = excluded.decompress,
+    priority = excluded.priority,
+    paused = 0",
+            )?
+            .execute(params![
+                download.account_id,
+                &download.product_id,
+                download.decompress,
+                download.priority,
+            ])?;
+
+        if let Some(download) = Self::get_one(&download.product_id)? {
+            Ok(download)
+        } else {
+            Err(Error::DatabaseCreatedItemNotAccessible)
+        }
+    }
+
+    pub fn reorder(product_ids: &[String]) -> Result<()> {
+        let mut connection = use_application().connection();
+        let tx = connection.transaction()?;
+        {
+            let mut update_stmt = tx.prepare(
+                "
+UPDATE download_queue
+SET
+    order_index = ?2
+WHERE product_id = ?1",
+            )?;
+
+            for (index, product_id) in product_ids.iter().enumerate() {
+                update_stmt.execute(params![product_id, index])?;
+            }
+        }
+        tx.commit()?;
+        Ok(())
+    }
+
+    pub fn update_one_paused(product_id: impl AsRef<str>, paused: bool) -> Result<()> {
+        use_application()
+            .connection()
+            .prepare(
+                "
+UPDATE download_queue
+SET
+    paused = ?2
+WHERE product_id = ?1",
+            )?
+            .execute(params![product_id.as_ref(), paused])?;
+        Ok(())
+    }
+
+    pub fn remove_one(product_id: impl AsRef<str>) -> Result<()> {
+        use_application()
+            .connection()
+            .prepare(
+                "
+DELETE FROM download_queue
+WHERE product_id = ?1",
+            )?
+            .execute(params![product_id.as_ref()])?;
+        Ok(())
+    }
+}
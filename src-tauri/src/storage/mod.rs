@@ -1,13 +1,18 @@
 use self::{
-    account::Account, display_language_setting::DisplayLanguageSetting,
-    latest_product_query::LatestProductQuery, product::Product, setting::Setting,
+    account::Account, archive_password::ArchivePassword,
+    display_language_setting::DisplayLanguageSetting, download_history::DownloadHistory,
+    download_queue::QueuedDownload, latest_product_query::LatestProductQuery, product::Product,
+    setting::Setting,
 };
 use crate::application_error::Result;
-use rusqlite::Connection;
+use rusqlite::{params, Connection, OptionalExtension};
 use std::path::Path;
 
 pub mod account;
+pub mod archive_password;
 pub mod display_language_setting;
+pub mod download_history;
+pub mod download_queue;
 pub mod latest_product_query;
 pub mod product;
 pub mod setting;
@@ -28,6 +33,9 @@ impl Storage {
     }
 
     pub fn prepare(&self) -> Result<()> {
+        let rebuild_product_index = self.drop_standalone_product_index()?;
+        let populate_product_owners = self.get_table_sql("product_owners")?.is_none();
+
         self.connection.execute_batch(&format!(
             "
 PRAGMA journal_mode = WAL;
@@ -39,6 +47,9 @@ BEGIN;
 {}
 {}
 {}
+{}
+{}
+{}
 COMMIT;
 ",
             Setting::get_ddl(),
@@ -46,8 +57,97 @@ COMMIT;
             Account::get_ddl(),
             Product::get_ddl(),
             LatestProductQuery::get_ddl(),
+            QueuedDownload::get_ddl(),
+            DownloadHistory::get_ddl(),
+            ArchivePassword::get_ddl(),
         ))?;
 
+        self.add_missing_columns("settings", Setting::get_columns())?;
+        self.add_missing_columns("accounts", Account::get_columns())?;
+        self.add_missing_columns("products", Product::get_columns())?;
+
+        if rebuild_product_index {
+            self.connection.execute_batch(
+                "
+INSERT INTO indexed_products (indexed_products) VALUES ('rebuild');",
+            )?;
+        }
+
+        // Products used to belong to the single account they were first synced with.
+        if populate_product_owners {
+            self.connection.execute_batch(
+                "
+INSERT INTO product_owners (
+    product_id,
+    account_id,
+    purchased_at
+) SELECT
+    product_id,
+    account_id,
+    purchased_at
+FROM products
+WHERE TRUE
+ON CONFLICT (product_id, account_id) DO NOTHING;",
+            )?;
+        }
+
+        Ok(())
+    }
+
+    fn get_table_sql(&self, name: &str) -> Result<Option<String>> {
+        Ok(self
+            .connection
+            .prepare(
+                "
+SELECT
+    sql
+FROM sqlite_master
+WHERE name = ?1",
+            )?
+            .query_row(params![name], |row| row.get::<_, String>("sql"))
+            .optional()?)
+    }
+
+    /// Older databases kept a standalone copy of the products in the index, duplicates included,
+    /// where it now reads them from the products table. Returns whether the index is to be rebuilt.
+    fn drop_standalone_product_index(&self) -> Result<bool> {
+        match self.get_table_sql("indexed_products")? {
+            Some(sql) if sql.contains("content = 'products'") => Ok(false),
+            Some(_) => {
+                self.connection.execute_batch(
+                    "
+DROP TABLE indexed_products;",
+                )?;
+                Ok(true)
+            }
+            None => Ok(true),
+        }
+    }
+
+    fn add_missing_columns(&self, table: &str, columns: &[(&str, &str)]) -> Result<()> {
+        let existing_columns = self
+            .connection
+            .prepare(
+                "
+SELECT
+    name
+FROM pragma_table_info(?1)",
+            )?
+            .query_map(params![table], |row| row.get::<_, String>("name"))?
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+
+        for (column, definition) in columns {
+            if existing_columns.iter().any(|existing| existing == column) {
+                continue;
+            }
+
+            self.connection.execute_batch(&format!(
+                "
+ALTER TABLE {} ADD COLUMN {} {};",
+                table, column, definition
+            ))?;
+        }
+
         Ok(())
     }
 
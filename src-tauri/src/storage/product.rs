@@ -11,7 +11,11 @@ use chrono::{DateTime, Utc};
 use rusqlite::{params, params_from_iter, OptionalExtension, Row};
 use serde::{Deserialize, Serialize};
 use serde_json::{json};
-use std::{path::PathBuf, str::FromStr};
+use std::{
+    collections::{HashMap, HashSet},
+    path::PathBuf,
+    str::FromStr,
+};
 use strum_macros::{EnumString, IntoStaticStr};
 
 use log::error;
@@ -19,14 +23,38 @@ use log::error;
 #[derive(Debug, Clone, Serialize)]
 pub struct Product {
     pub id: i64,
+    /// The account the product is downloaded with first.
     pub account: Account,
+    /// Every account that purchased the product, earliest purchase first.
+    pub owners: Vec<ProductOwner>,
     pub product: DLsiteProduct,
     pub download: Option<ProductDownload>,
+    /// Set once the product stops showing up in the purchases of its account, e.g. after a refund.
+    pub revoked_at: Option<DateTime<Utc>>,
     pub created_at: DateTime<Utc>,
     pub updated_at: DateTime<Utc>,
     pub json: String
 }
 
+#[derive(Debug, Clone, Serialize)]
+pub struct ProductOwner {
+    pub account_id: i64,
+    pub username: String,
+    pub purchased_at: DateTime<Utc>,
+}
+
+impl<'stmt> TryFrom<&'stmt Row<'stmt>> for ProductOwner {
+    type Error = rusqlite::Error;
+
+    fn try_from(row: &'stmt Row<'stmt>) -> std::result::Result<Self, Self::Error> {
+        Ok(Self {
+            account_id: row.get("account_id")?,
+            username: row.get("account_username")?,
+            purchased_at: row.get("purchased_at")?,
+        })
+    }
+}
+
 #[derive(Debug, Clone, Serialize)]
 pub struct ProductDownload {
     pub id: i64,
@@ -47,7 +75,17 @@ impl<'stmt> TryFrom<&'stmt Row<'stmt>> for ProductDownload {
 }
 
 #[derive(
-    EnumString, IntoStaticStr, Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize,
+    EnumString,
+    IntoStaticStr,
+    Default,
+    Debug,
+    Clone,
+    Copy,
+    PartialEq,
+    Eq,
+    Hash,
+    Serialize,
+    Deserialize,
 )]
 pub enum ProductQueryOrderBy {
     IdAsc,
@@ -59,6 +97,7 @@ pub enum ProductQueryOrderBy {
     RegistrationDateAsc,
     RegistrationDateDesc,
     PurchaseDateAsc,
+    #[default]
     PurchaseDateDesc,
 }
 
@@ -72,12 +111,6 @@ pub enum ProductDownloadState {
     DownloadingAndDownloaded,
 }
 
-impl Default for ProductQueryOrderBy {
-    fn default() -> Self {
-        Self::PurchaseDateDesc
-    }
-}
-
 #[derive(Default, Debug, Clone, Serialize, Deserialize)]
 pub struct ProductQuery {
     pub query: Option<String>,
@@ -129,6 +162,7 @@ impl<'stmt> TryFrom<&'stmt Row<'stmt>> for Product {
                 created_at: row.get("account_created_at")?,
                 updated_at: row.get("account_updated_at")?,
             },
+            owners: Vec::new(),
             product: DLsiteProduct {
                 id: row.get("product_id")?,
                 ty: <_>::from_str(&row.get::<_, String>("product_type")?).map_err(
@@ -185,6 +219,7 @@ impl<'stmt> TryFrom<&'stmt Row<'stmt>> for Product {
                     None
                 }
             },
+            revoked_at: row.get("revoked_at")?,
             created_at: row.get("created_at")?,
             updated_at: row.get("updated_at")?,
             json: data,
@@ -242,9 +277,233 @@ CREATE VIRTUAL TABLE IF NOT EXISTS indexed_products USING fts5 (
     product_group_name_ko,
     product_group_name_tw,
     product_group_name_cn,
+    content = 'products',
+    content_rowid = 'id',
     tokenize = 'trigram'
 );
 
+CREATE TRIGGER IF NOT EXISTS products_index_insert AFTER INSERT ON products
+BEGIN
+    INSERT INTO indexed_products (
+        rowid,
+        product_id,
+        product_title_ja,
+        product_title_en,
+        product_title_ko,
+        product_title_tw,
+        product_title_cn,
+        product_group_id,
+        product_group_name_ja,
+        product_group_name_en,
+        product_group_name_ko,
+        product_group_name_tw,
+        product_group_name_cn
+    ) VALUES (
+        NEW.id,
+        NEW.product_id,
+        NEW.product_title_ja,
+        NEW.product_title_en,
+        NEW.product_title_ko,
+        NEW.product_title_tw,
+        NEW.product_title_cn,
+        NEW.product_group_id,
+        NEW.product_group_name_ja,
+        NEW.product_group_name_en,
+        NEW.product_group_name_ko,
+        NEW.product_group_name_tw,
+        NEW.product_group_name_cn
+    );
+END;
+
+CREATE TRIGGER IF NOT EXISTS products_index_delete AFTER DELETE ON products
+BEGIN
+    INSERT INTO indexed_products (
+        indexed_products,
+        rowid,
+        product_id,
+        product_title_ja,
+        product_title_en,
+        product_title_ko,
+        product_title_tw,
+        product_title_cn,
+        product_group_id,
+        product_group_name_ja,
+        product_group_name_en,
+        product_group_name_ko,
+        product_group_name_tw,
+        product_group_name_cn
+    ) VALUES (
+        'delete',
+        OLD.id,
+        OLD.product_id,
+        OLD.product_title_ja,
+        OLD.product_title_en,
+        OLD.product_title_ko,
+        OLD.product_title_tw,
+        OLD.product_title_cn,
+        OLD.product_group_id,
+        OLD.product_group_name_ja,
+        OLD.product_group_name_en,
+        OLD.product_group_name_ko,
+        OLD.product_group_name_tw,
+        OLD.product_group_name_cn
+    );
+END;
+
+CREATE TRIGGER IF NOT EXISTS products_index_update AFTER UPDATE OF
+    product_id,
+    product_title_ja,
+    product_title_en,
+    product_title_ko,
+    product_title_tw,
+    product_title_cn,
+    product_group_id,
+    product_group_name_ja,
+    product_group_name_en,
+    product_group_name_ko,
+    product_group_name_tw,
+    product_group_name_cn
+ON products
+BEGIN
+    INSERT INTO indexed_products (
+        indexed_products,
+        rowid,
+        product_id,
+        product_title_ja,
+        product_title_en,
+        product_title_ko,
+        product_title_tw,
+        product_title_cn,
+        product_group_id,
+        product_group_name_ja,
+        product_group_name_en,
+        product_group_name_ko,
+        product_group_name_tw,
+        product_group_name_cn
+    ) VALUES (
+        'delete',
+        OLD.id,
+        OLD.product_id,
+        OLD.product_title_ja,
+        OLD.product_title_en,
+        OLD.product_title_ko,
+        OLD.product_title_tw,
+        OLD.product_title_cn,
+        OLD.product_group_id,
+        OLD.product_group_name_ja,
+        OLD.product_group_name_en,
+        OLD.product_group_name_ko,
+        OLD.product_group_name_tw,
+        OLD.product_group_name_cn
+    );
+    INSERT INTO indexed_products (
+        rowid,
+        product_id,
+        product_title_ja,
+        product_title_en,
+        product_title_ko,
+        product_title_tw,
+        product_title_cn,
+        product_group_id,
+        product_group_name_ja,
+        product_group_name_en,
+        product_group_name_ko,
+        product_group_name_tw,
+        product_group_name_cn
+    ) VALUES (
+        NEW.id,
+        NEW.product_id,
+        NEW.product_title_ja,
+        NEW.product_title_en,
+        NEW.product_title_ko,
+        NEW.product_title_tw,
+        NEW.product_title_cn,
+        NEW.product_group_id,
+        NEW.product_group_name_ja,
+        NEW.product_group_name_en,
+        NEW.product_group_name_ko,
+        NEW.product_group_name_tw,
+        NEW.product_group_name_cn
+    );
+END;
+
+CREATE TABLE IF NOT EXISTS product_owners (
+    id INTEGER PRIMARY KEY NOT NULL,
+    product_id TEXT NOT NULL,
+    account_id INTEGER NOT NULL,
+    purchased_at INTEGER NOT NULL,
+    created_at INTEGER NOT NULL DEFAULT CURRENT_TIMESTAMP,
+
+    UNIQUE(product_id, account_id),
+    FOREIGN KEY(product_id) REFERENCES products(product_id) ON UPDATE CASCADE ON DELETE CASCADE,
+    FOREIGN KEY(account_id) REFERENCES accounts(id) ON UPDATE CASCADE ON DELETE CASCADE
+);
+
+CREATE INDEX IF NOT EXISTS product_owners_account_id ON product_owners (account_id);
+
+CREATE TRIGGER IF NOT EXISTS product_owners_insert AFTER INSERT ON product_owners
+BEGIN
+    UPDATE products SET purchased_at = (
+        SELECT MIN(purchased_at) FROM product_owners WHERE product_id = NEW.product_id
+    )
+    WHERE product_id = NEW.product_id
+    AND purchased_at IS NOT (
+        SELECT MIN(purchased_at) FROM product_owners WHERE product_id = NEW.product_id
+    );
+END;
+
+CREATE TRIGGER IF NOT EXISTS product_owners_update AFTER UPDATE OF purchased_at ON product_owners
+BEGIN
+    UPDATE products SET purchased_at = (
+        SELECT MIN(purchased_at) FROM product_owners WHERE product_id = NEW.product_id
+    )
+    WHERE product_id = NEW.product_id
+    AND purchased_at IS NOT (
+        SELECT MIN(purchased_at) FROM product_owners WHERE product_id = NEW.product_id
+    );
+END;
+
+CREATE TRIGGER IF NOT EXISTS product_owners_delete AFTER DELETE ON product_owners
+BEGIN
+    UPDATE products SET account_id = (
+        SELECT account_id
+        FROM product_owners
+        WHERE product_id = OLD.product_id
+        ORDER BY purchased_at ASC, account_id ASC
+        LIMIT 1
+    )
+    WHERE product_id = OLD.product_id
+    AND account_id = OLD.account_id
+    AND EXISTS (SELECT 1 FROM product_owners WHERE product_id = OLD.product_id);
+    UPDATE products SET purchased_at = (
+        SELECT MIN(purchased_at) FROM product_owners WHERE product_id = OLD.product_id
+    )
+    WHERE product_id = OLD.product_id
+    AND EXISTS (SELECT 1 FROM product_owners WHERE product_id = OLD.product_id)
+    AND purchased_at IS NOT (
+        SELECT MIN(purchased_at) FROM product_owners WHERE product_id = OLD.product_id
+    );
+END;
+
+CREATE TRIGGER IF NOT EXISTS accounts_product_handover BEFORE DELETE ON accounts
+BEGIN
+    UPDATE products SET account_id = (
+        SELECT owner.account_id
+        FROM product_owners AS owner
+        WHERE owner.product_id = products.product_id
+        AND owner.account_id != OLD.id
+        ORDER BY owner.purchased_at ASC, owner.account_id ASC
+        LIMIT 1
+    )
+    WHERE account_id = OLD.id
+    AND EXISTS (
+        SELECT 1
+        FROM product_owners AS owner
+        WHERE owner.product_id = products.product_id
+        AND owner.account_id != OLD.id
+    );
+END;
+
 CREATE TABLE IF NOT EXISTS product_downloads (
     id INTEGER PRIMARY KEY NOT NULL,
     product_id TEXT NOT NULL UNIQUE,
@@ -263,6 +522,10 @@ CREATE TABLE IF NOT EXISTS product_jsons (
 );"
     }
 
+    pub fn get_columns() -> &'static [(&'static str, &'static str)] {
+        &[("revoked_at", "INTEGER")]
+    }
+
     pub fn list_all(query: &ProductQuery) -> Result<Vec<Self>> {
         let mut where_clause = "TRUE".to_owned();
         let mut params = Vec::new();
@@ -270,7 +533,9 @@ CREATE TABLE IF NOT EXISTS product_jsons (
         if let Some(query) = &query.query {
             let query = query.trim();
             if query.len() != 0 {
-                where_clause.push_str(" AND indexed_products MATCH ?");
+                where_clause.push_str(
+                    " AND product.id IN (SELECT rowid FROM indexed_products WHERE indexed_products MATCH ?)",
+                );
                 params.push(query.to_owned());
             }
         }
@@ -306,7 +571,7 @@ CREATE TABLE IF NOT EXISTS product_jsons (
             ProductQueryOrderBy::PurchaseDateDesc => "product.purchased_at DESC, product.id DESC",
         };
 
-        Ok(use_application()
+        let mut products = use_application()
             .connection()
             .prepare(&format!(
                 "
@@ -339,23 +604,81 @@ SELECT
     product.registered_at,
     product.upgraded_at,
     product.purchased_at,
+    product.revoked_at,
     product.created_at,
     product.updated_at,
     download.id as download_id,
     download.path as download_path,
     download.created_at as download_created_at,
     jsons.json as pjson
-FROM indexed_products
-INNER JOIN products AS product ON product.product_id = indexed_products.product_id
+FROM products AS product
 INNER JOIN accounts AS account ON account.id = product.account_id
-LEFT JOIN product_downloads as download ON download.product_id = indexed_products.product_id
-LEFT JOIN product_jsons as jsons ON jsons.product_id = indexed_products.product_id
+LEFT JOIN product_downloads as download ON download.product_id = product.product_id
+LEFT JOIN product_jsons as jsons ON jsons.product_id = product.product_id
 WHERE {}
-GROUP BY product.product_id
 ORDER BY {}",
                 where_clause, order_by_clause
             ))?
             .query_map(params_from_iter(&params), |row| Self::try_from(row))?
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+        let mut owners = Self::list_all_owners(&where_clause, &params)?;
+
+        for product in &mut products {
+            product.owners = owners.remove(&product.product.id).unwrap_or_default();
+        }
+
+        Ok(products)
+    }
+
+    /// Lists the owners of the products matching the same `WHERE` clause as [`Product::list_all`].
+    fn list_all_owners(
+        where_clause: &str,
+        params: &[String],
+    ) -> Result<HashMap<String, Vec<ProductOwner>>> {
+        let mut owners = HashMap::<_, Vec<_>>::new();
+
+        for owner in use_application()
+            .connection()
+            .prepare(&format!(
+                "
+SELECT
+    owner.product_id,
+    owner.account_id,
+    owner.purchased_at,
+    account.username AS account_username
+FROM product_owners AS owner
+INNER JOIN accounts AS account ON account.id = owner.account_id
+WHERE owner.product_id IN (SELECT product.product_id FROM products AS product WHERE {})
+ORDER BY owner.purchased_at ASC, owner.account_id ASC",
+                where_clause
+            ))?
+            .query_map(params_from_iter(params), |row| {
+                Ok((
+                    row.get::<_, String>("product_id")?,
+                    ProductOwner::try_from(row)?,
+                ))
+            })?
+        {
+            let (product_id, owner) = owner?;
+            owners.entry(product_id).or_default().push(owner);
+        }
+
+        Ok(owners)
+    }
+
+    /// Accounts that purchased the product, earliest purchase first.
+    pub fn list_owner_account_ids(product_id: impl AsRef<str>) -> Result<Vec<i64>> {
+        Ok(use_application()
+            .connection()
+            .prepare(
+                "
+SELECT
+    account_id
+FROM product_owners
+WHERE product_id = ?1
+ORDER BY purchased_at ASC, account_id ASC",
+            )?
+            .query_map(params![product_id.as_ref()], |row| row.get("account_id"))?
             .collect::<rusqlite::Result<Vec<_>>>()?)
     }
 
@@ -377,6 +700,20 @@ WHERE product_id = ?1",
             .optional()?)
     }
 
+    pub fn list_all_download_product_ids() -> Result<Vec<String>> {
+        Ok(use_application()
+            .connection()
+            .prepare(
+                "
+SELECT
+    product_id
+FROM product_downloads
+ORDER BY product_id",
+            )?
+            .query_map((), |row| row.get("product_id"))?
+            .collect::<rusqlite::Result<Vec<_>>>()?)
+    }
+
     pub fn get_json(product_id: impl AsRef<str>) -> Result<String> {
         Ok(use_application()
             .connection()
@@ -392,6 +729,64 @@ WHERE product_id = ?1",
             })?)
     }
 
+    pub fn get_one_type(product_id: impl AsRef<str>) -> Result<Option<DLsiteProductType>> {
+        Ok(use_application()
+            .connection()
+            .prepare(
+                "
+SELECT
+    product_type
+FROM products
+WHERE product_id = ?1",
+            )?
+            .query_row(params![product_id.as_ref()], |row| {
+                <_>::from_str(&row.get::<_, String>("product_type")?).map_err(
+                    |err: strum::ParseError| {
+                        rusqlite::Error::FromSqlConversionFailure(
+                            row.as_ref().column_index("product_type").unwrap(),
+                            rusqlite::types::Type::Text,
+                            Box::new(err),
+                        )
+                    },
+                )
+            })
+            .optional()?)
+    }
+
+    /// Titles of the product in every language it has one in.
+    pub fn get_one_titles(product_id: impl AsRef<str>) -> Result<Vec<String>> {
+        Ok(use_application()
+            .connection()
+            .prepare(
+                "
+SELECT
+    product_title_ja,
+    product_title_en,
+    product_title_ko,
+    product_title_tw,
+    product_title_cn
+FROM products
+WHERE product_id = ?1",
+            )?
+            .query_row(params![product_id.as_ref()], |row| {
+                [
+                    "product_title_ja",
+                    "product_title_en",
+                    "product_title_ko",
+                    "product_title_tw",
+                    "product_title_cn",
+                ]
+                .iter()
+                .map(|&column| row.get::<_, Option<String>>(column))
+                .collect::<rusqlite::Result<Vec<_>>>()
+            })
+            .optional()?
+            .unwrap_or_default()
+            .into_iter()
+            .flatten()
+            .collect())
+    }
+
     
 
     pub fn insert_all(mut products: impl Iterator<Item = InsertedProduct>) -> Result<()> {
@@ -442,12 +837,209 @@ INSERT INTO products (
     ?18,
     ?19,
     ?20
-) ON CONFLICT (product_id) DO NOTHING",
+) ON CONFLICT (product_id) DO UPDATE SET
+    product_type = excluded.product_type,
+    product_age = excluded.product_age,
+    product_title_ja = excluded.product_title_ja,
+    product_title_en = excluded.product_title_en,
+    product_title_ko = excluded.product_title_ko,
+    product_title_tw = excluded.product_title_tw,
+    product_title_cn = excluded.product_title_cn,
+    product_group_id = excluded.product_group_id,
+    product_group_name_ja = excluded.product_group_name_ja,
+    product_group_name_en = excluded.product_group_name_en,
+    product_group_name_ko = excluded.product_group_name_ko,
+    product_group_name_tw = excluded.product_group_name_tw,
+    product_group_name_cn = excluded.product_group_name_cn,
+    product_icon_main = excluded.product_icon_main,
+    product_icon_small = excluded.product_icon_small,
+    registered_at = excluded.registered_at,
+    upgraded_at = excluded.upgraded_at
+WHERE product_type IS NOT excluded.product_type
+OR product_age IS NOT excluded.product_age
+OR product_title_ja IS NOT excluded.product_title_ja
+OR product_title_en IS NOT excluded.product_title_en
+OR product_title_ko IS NOT excluded.product_title_ko
+OR product_title_tw IS NOT excluded.product_title_tw
+OR product_title_cn IS NOT excluded.product_title_cn
+OR product_group_id IS NOT excluded.product_group_id
+OR product_group_name_ja IS NOT excluded.product_group_name_ja
+OR product_group_name_en IS NOT excluded.product_group_name_en
+OR product_group_name_ko IS NOT excluded.product_group_name_ko
+OR product_group_name_tw IS NOT excluded.product_group_name_tw
+OR product_group_name_cn IS NOT excluded.product_group_name_cn
+OR product_icon_main IS NOT excluded.product_icon_main
+OR product_icon_small IS NOT excluded.product_icon_small
+OR registered_at IS NOT excluded.registered_at
+OR upgraded_at IS NOT excluded.upgraded_at",
+            )?;
+            let mut owner_stmt = tx.prepare(
+                "
+INSERT INTO product_owners (
+    product_id,
+    account_id,
+    purchased_at
+) VALUES (
+    ?1,
+    ?2,
+    ?3
+) ON CONFLICT (product_id, account_id) DO UPDATE SET
+    purchased_at = excluded.purchased_at
+WHERE purchased_at IS NOT excluded.purchased_at",
+            )?;
+
+            while let Some(product) = products.next() {
+                insert_stmt.execute(params![
+                    product.account_id,
+                    &product.product.id,
+                    product.product.ty.to_string(),
+                    product.product.age.to_string(),
+                    &product.product.title.japanese,
+                    &product.product.title.english,
+                    &product.product.title.korean,
+                    &product.product.title.taiwanese,
+                    &product.product.title.chinese,
+                    &product.product.group.id,
+                    &product.product.group.name.japanese,
+                    &product.product.group.name.english,
+                    &product.product.group.name.korean,
+                    &product.product.group.name.taiwanese,
+                    &product.product.group.name.chinese,
+                    product.product.icon.main,
+                    product.product.icon.small,
+                    product.product.registered_at,
+                    product.product.upgraded_at,
+                    product.product.purchased_at,
+                ])?;
+                owner_stmt.execute(params![
+                    &product.product.id,
+                    product.account_id,
+                    product.product.purchased_at,
+                ])?;
+            }
+        }
+        tx.commit()?;
+        Ok(())
+    }
+
+    /// Drops the ownership of the account over the products that are not among `product_ids`,
+    /// revoking the ones no other account owns, and restores the ones that are back.
+    pub fn update_all_revoked(account_id: i64, product_ids: &HashSet<String>) -> Result<()> {
+        let mut connection = use_application().connection();
+        let tx = connection.transaction()?;
+        {
+            let products = tx
+                .prepare(
+                    "
+SELECT
+    owner.product_id,
+    product.revoked_at IS NOT NULL AS revoked,
+    (
+        SELECT COUNT(*)
+        FROM product_owners AS other
+        WHERE other.product_id = owner.product_id
+    ) AS owner_count
+FROM product_owners AS owner
+INNER JOIN products AS product ON product.product_id = owner.product_id
+WHERE owner.account_id = ?1",
+                )?
+                .query_map(params![account_id], |row| {
+                    Ok((
+                        row.get::<_, String>("product_id")?,
+                        row.get("revoked")?,
+                        row.get::<_, i64>("owner_count")?,
+                    ))
+                })?
+                .collect::<rusqlite::Result<Vec<_>>>()?;
+            let mut disown_stmt = tx.prepare(
+                "
+DELETE FROM product_owners
+WHERE product_id = ?1
+AND account_id = ?2",
             )?;
-            let mut index_stmt = tx.prepare(
+            let mut revoke_stmt = tx.prepare(
                 "
-INSERT INTO indexed_products (
+UPDATE products
+SET
+    revoked_at = CURRENT_TIMESTAMP
+WHERE product_id = ?1",
+            )?;
+            let mut restore_stmt = tx.prepare(
+                "
+UPDATE products
+SET
+    revoked_at = NULL
+WHERE product_id = ?1",
+            )?;
+
+            for (product_id, revoked, owner_count) in products {
+                match (product_ids.contains(&product_id), revoked) {
+                    (false, _) if 1 < owner_count => {
+                        disown_stmt.execute(params![product_id, account_id])?
+                    }
+                    (false, false) => revoke_stmt.execute(params![product_id])?,
+                    (true, true) => restore_stmt.execute(params![product_id])?,
+                    _ => 0,
+                };
+            }
+        }
+        tx.commit()?;
+        Ok(())
+    }
+
+    /// Prepares an empty staging table for [`Product::stage_all`]. It lives as long as the
+    /// connection, so nothing is left behind if the refresh fails halfway.
+    pub fn begin_staging() -> Result<()> {
+        use_application().connection().execute_batch(
+            "
+CREATE TEMP TABLE IF NOT EXISTS staged_products (
+    account_id INTEGER NOT NULL,
+    product_id TEXT NOT NULL PRIMARY KEY,
+    product_type TEXT NOT NULL,
+    product_age TEXT NOT NULL,
+    product_title_ja TEXT,
+    product_title_en TEXT,
+    product_title_ko TEXT,
+    product_title_tw TEXT,
+    product_title_cn TEXT,
+    product_group_id TEXT NOT NULL,
+    product_group_name_ja TEXT,
+    product_group_name_en TEXT,
+    product_group_name_ko TEXT,
+    product_group_name_tw TEXT,
+    product_group_name_cn TEXT,
+    product_icon_main TEXT NOT NULL,
+    product_icon_small TEXT NOT NULL,
+    registered_at INTEGER,
+    upgraded_at INTEGER,
+    purchased_at INTEGER NOT NULL
+);
+CREATE TEMP TABLE IF NOT EXISTS staged_product_owners (
+    product_id TEXT NOT NULL,
+    account_id INTEGER NOT NULL,
+    purchased_at INTEGER NOT NULL,
+
+    PRIMARY KEY(product_id, account_id)
+);
+DELETE FROM staged_products;
+DELETE FROM staged_product_owners;",
+        )?;
+        Ok(())
+    }
+
+    /// Stages fetched products along with the account that owns them. The details staged first
+    /// are kept.
+    pub fn stage_all(mut products: impl Iterator<Item = InsertedProduct>) -> Result<()> {
+        let mut connection = use_application().connection();
+        let tx = connection.transaction()?;
+        {
+            let mut stage_stmt = tx.prepare(
+                "
+INSERT INTO staged_products (
+    account_id,
     product_id,
+    product_type,
+    product_age,
     product_title_ja,
     product_title_en,
     product_title_ko,
@@ -458,7 +1050,12 @@ INSERT INTO indexed_products (
     product_group_name_en,
     product_group_name_ko,
     product_group_name_tw,
-    product_group_name_cn
+    product_group_name_cn,
+    product_icon_main,
+    product_icon_small,
+    registered_at,
+    upgraded_at,
+    purchased_at
 ) VALUES (
     ?1,
     ?2,
@@ -471,12 +1068,32 @@ INSERT INTO indexed_products (
     ?9,
     ?10,
     ?11,
-    ?12
-)",
+    ?12,
+    ?13,
+    ?14,
+    ?15,
+    ?16,
+    ?17,
+    ?18,
+    ?19,
+    ?20
+) ON CONFLICT (product_id) DO NOTHING",
+            )?;
+            let mut owner_stmt = tx.prepare(
+                "
+INSERT INTO staged_product_owners (
+    product_id,
+    account_id,
+    purchased_at
+) VALUES (
+    ?1,
+    ?2,
+    ?3
+) ON CONFLICT (product_id, account_id) DO NOTHING",
             )?;
 
             while let Some(product) = products.next() {
-                insert_stmt.execute(params![
+                stage_stmt.execute(params![
                     product.account_id,
                     &product.product.id,
                     product.product.ty.to_string(),
@@ -498,19 +1115,10 @@ INSERT INTO indexed_products (
                     product.product.upgraded_at,
                     product.product.purchased_at,
                 ])?;
-                index_stmt.execute(params![
+                owner_stmt.execute(params![
                     &product.product.id,
-                    &product.product.title.japanese,
-                    &product.product.title.english,
-                    &product.product.title.korean,
-                    &product.product.title.taiwanese,
-                    &product.product.title.chinese,
-                    &product.product.group.id,
-                    &product.product.group.name.japanese,
-                    &product.product.group.name.english,
-                    &product.product.group.name.korean,
-                    &product.product.group.name.taiwanese,
-                    &product.product.group.name.chinese,
+                    product.account_id,
+                    product.product.purchased_at,
                 ])?;
             }
         }
@@ -518,6 +1126,123 @@ INSERT INTO indexed_products (
         Ok(())
     }
 
+    /// Upserts the staged products and their owners in one transaction, leaving their downloads
+    /// and cached details in place. Accounts among `account_ids` lose the products they were not
+    /// staged for, except for the last owner of a product, which is marked as revoked instead.
+    pub fn commit_staged(account_ids: &[i64]) -> Result<()> {
+        let mut connection = use_application().connection();
+        let tx = connection.transaction()?;
+        tx.execute_batch(
+            "
+INSERT INTO products (
+    account_id,
+    product_id,
+    product_type,
+    product_age,
+    product_title_ja,
+    product_title_en,
+    product_title_ko,
+    product_title_tw,
+    product_title_cn,
+    product_group_id,
+    product_group_name_ja,
+    product_group_name_en,
+    product_group_name_ko,
+    product_group_name_tw,
+    product_group_name_cn,
+    product_icon_main,
+    product_icon_small,
+    registered_at,
+    upgraded_at,
+    purchased_at
+) SELECT
+    account_id,
+    product_id,
+    product_type,
+    product_age,
+    product_title_ja,
+    product_title_en,
+    product_title_ko,
+    product_title_tw,
+    product_title_cn,
+    product_group_id,
+    product_group_name_ja,
+    product_group_name_en,
+    product_group_name_ko,
+    product_group_name_tw,
+    product_group_name_cn,
+    product_icon_main,
+    product_icon_small,
+    registered_at,
+    upgraded_at,
+    purchased_at
+FROM staged_products
+WHERE TRUE
+ON CONFLICT (product_id) DO UPDATE SET
+    product_type = excluded.product_type,
+    product_age = excluded.product_age,
+    product_title_ja = excluded.product_title_ja,
+    product_title_en = excluded.product_title_en,
+    product_title_ko = excluded.product_title_ko,
+    product_title_tw = excluded.product_title_tw,
+    product_title_cn = excluded.product_title_cn,
+    product_group_id = excluded.product_group_id,
+    product_group_name_ja = excluded.product_group_name_ja,
+    product_group_name_en = excluded.product_group_name_en,
+    product_group_name_ko = excluded.product_group_name_ko,
+    product_group_name_tw = excluded.product_group_name_tw,
+    product_group_name_cn = excluded.product_group_name_cn,
+    product_icon_main = excluded.product_icon_main,
+    product_icon_small = excluded.product_icon_small,
+    registered_at = excluded.registered_at,
+    upgraded_at = excluded.upgraded_at,
+    revoked_at = NULL;
+INSERT INTO product_owners (
+    product_id,
+    account_id,
+    purchased_at
+) SELECT
+    product_id,
+    account_id,
+    purchased_at
+FROM staged_product_owners
+WHERE TRUE
+ON CONFLICT (product_id, account_id) DO UPDATE SET
+    purchased_at = excluded.purchased_at
+WHERE purchased_at IS NOT excluded.purchased_at;",
+        )?;
+        {
+            let mut disown_stmt = tx.prepare(
+                "
+DELETE FROM product_owners
+WHERE account_id = ?1
+AND product_id NOT IN (SELECT product_id FROM staged_product_owners WHERE account_id = ?1)
+AND product_id IN (SELECT product_id FROM product_owners WHERE account_id != ?1)",
+            )?;
+            let mut revoke_stmt = tx.prepare(
+                "
+UPDATE products
+SET
+    revoked_at = CURRENT_TIMESTAMP
+WHERE revoked_at IS NULL
+AND product_id IN (SELECT product_id FROM product_owners WHERE account_id = ?1)
+AND product_id NOT IN (SELECT product_id FROM staged_product_owners)",
+            )?;
+
+            for account_id in account_ids {
+                disown_stmt.execute(params![account_id])?;
+                revoke_stmt.execute(params![account_id])?;
+            }
+        }
+        tx.execute_batch(
+            "
+DELETE FROM staged_products;
+DELETE FROM staged_product_owners;",
+        )?;
+        tx.commit()?;
+        Ok(())
+    }
+
     pub fn insert_download(
         product_id: impl AsRef<str>,
         path: impl AsRef<str>,
@@ -532,7 +1257,9 @@ INSERT INTO product_downloads (
 ) VALUES (
     ?1,
     ?2
-)
+) ON CONFLICT (product_id) DO UPDATE SET
+    path = excluded.path,
+    created_at = CURRENT_TIMESTAMP
             ",
             )?
             .insert(params![product_id.as_ref(), path.as_ref()])?;
@@ -570,7 +1297,6 @@ REPLACE INTO product_jsons (
         use_application().connection().execute_batch(
             "
 DELETE FROM products;
-DELETE FROM indexed_products;
 VACUUM;",
         )?;
         Ok(())
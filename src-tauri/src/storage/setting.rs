@@ -1,11 +1,141 @@
-use crate::{application::use_application, application_error::Result};
-use rusqlite::{params, OptionalExtension, Row};
+use crate::{
+    application::use_application, application_error::Result, dlsite::api::DLsiteProductType,
+};
+use chrono::NaiveTime;
+use rusqlite::{params, types::Type, OptionalExtension, Row};
 use serde::{Deserialize, Serialize};
-use std::path::PathBuf;
+use std::{path::PathBuf, str::FromStr};
+use strum_macros::{EnumString, IntoStaticStr};
+
+static DEFAULT_MAX_CONCURRENT_DOWNLOADS: usize = 3;
+static DEFAULT_DOWNLOAD_MAX_RETRIES: u32 = 5;
+static MAX_DOWNLOAD_SEGMENTS: usize = 16;
+static MAX_NESTED_EXTRACTION_DEPTH: u32 = 8;
+static DEFAULT_SYNC_CONCURRENT_ACCOUNTS: usize = 3;
+// Pages are fetched per account, so a sync sends at most 8 * 4 = 32 requests at once.
+static MAX_SYNC_CONCURRENT_ACCOUNTS: usize = 8;
+static MAX_SYNC_CONCURRENT_PAGES: usize = 4;
 
 #[derive(Default, Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
 pub struct Setting {
     pub download_root_dir: Option<PathBuf>,
+    pub max_concurrent_downloads: Option<usize>,
+    pub download_max_retries: Option<u32>,
+    pub download_rate_limit: Option<u64>,
+    pub download_windows: Vec<DownloadWindow>,
+    pub download_segments: Option<usize>,
+    pub zip_filename_encoding: Option<ZipFilenameEncoding>,
+    pub nested_extraction_depth: Option<u32>,
+    pub product_type_settings: Vec<ProductTypeSetting>,
+    /// Tried on password protected archives of products without a password of their own.
+    pub archive_passwords: Vec<String>,
+    pub flatten_policy: Option<FlattenPolicy>,
+    pub keep_archives: Option<KeepArchives>,
+    /// Where archives kept under [`KeepArchives::ArchiveRoot`] go, one folder per product.
+    pub archive_root_dir: Option<PathBuf>,
+    /// Accounts synced at once while refreshing the product list.
+    pub sync_concurrent_accounts: Option<usize>,
+    /// Pages of a single account fetched at once while syncing the product list.
+    pub sync_concurrent_pages: Option<usize>,
+}
+
+/// Overrides of the global settings for products of a single type.
+#[derive(Default, Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ProductTypeSetting {
+    pub product_type: String,
+    pub nested_extraction_depth: Option<u32>,
+    pub keep_archives: Option<KeepArchives>,
+}
+
+/// Forces the encoding of ZIP entry names that are not flagged as UTF-8.
+#[derive(
+    EnumString, IntoStaticStr, Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize,
+)]
+pub enum ZipFilenameEncoding {
+    Utf8,
+    Cp932,
+    Cp437,
+    Gbk,
+    Big5,
+    EucKr,
+}
+
+/// Decides when a single top-level directory holding all the extracted files is dropped.
+#[derive(
+    EnumString,
+    IntoStaticStr,
+    Default,
+    Debug,
+    Clone,
+    Copy,
+    PartialEq,
+    Eq,
+    Hash,
+    Serialize,
+    Deserialize,
+)]
+pub enum FlattenPolicy {
+    Never,
+    #[default]
+    SingleDir,
+    /// Only a directory named after the product ID or title, or after the archive for nested ones.
+    MatchingDir,
+}
+
+/// Decides what happens to the original archives once they have been extracted.
+#[derive(
+    EnumString,
+    IntoStaticStr,
+    Default,
+    Debug,
+    Clone,
+    Copy,
+    PartialEq,
+    Eq,
+    Hash,
+    Serialize,
+    Deserialize,
+)]
+pub enum KeepArchives {
+    #[default]
+    Never,
+    ProductFolder,
+    ArchiveRoot,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DownloadWindow {
+    #[serde(with = "hour_minute")]
+    pub start: NaiveTime,
+    #[serde(with = "hour_minute")]
+    pub end: NaiveTime,
+}
+
+impl DownloadWindow {
+    pub fn contains(&self, time: NaiveTime) -> bool {
+        if self.start <= self.end {
+            self.start == self.end || (self.start <= time && time < self.end)
+        } else {
+            // The window spans midnight, e.g. 23:00-06:00.
+            self.start <= time || time < self.end
+        }
+    }
+}
+
+mod hour_minute {
+    use chrono::NaiveTime;
+    use serde::{de::Error, Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(time: &NaiveTime, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&time.format("%H:%M").to_string())
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<NaiveTime, D::Error> {
+        NaiveTime::parse_from_str(&String::deserialize(deserializer)?, "%H:%M")
+            .map_err(D::Error::custom)
+    }
 }
 
 impl<'stmt> TryFrom<&'stmt Row<'stmt>> for Setting {
@@ -16,6 +146,85 @@ impl<'stmt> TryFrom<&'stmt Row<'stmt>> for Setting {
             download_root_dir: row
                 .get::<_, Option<String>>("download_root_dir")?
                 .map(|path| PathBuf::from(path)),
+            max_concurrent_downloads: row.get("max_concurrent_downloads")?,
+            download_max_retries: row.get("download_max_retries")?,
+            download_rate_limit: row.get("download_rate_limit")?,
+            download_windows: match row.get::<_, Option<String>>("download_windows")? {
+                Some(json) => serde_json::from_str(&json).map_err(|err| {
+                    rusqlite::Error::FromSqlConversionFailure(
+                        row.as_ref().column_index("download_windows").unwrap(),
+                        Type::Text,
+                        Box::new(err),
+                    )
+                })?,
+                None => Vec::new(),
+            },
+            download_segments: row.get("download_segments")?,
+            zip_filename_encoding: row
+                .get_ref("zip_filename_encoding")?
+                .as_str_or_null()?
+                .map(|encoding| {
+                    <_>::from_str(encoding).map_err(|err: strum::ParseError| {
+                        rusqlite::Error::FromSqlConversionFailure(
+                            row.as_ref().column_index("zip_filename_encoding").unwrap(),
+                            Type::Text,
+                            Box::new(err),
+                        )
+                    })
+                })
+                .transpose()?,
+            nested_extraction_depth: row.get("nested_extraction_depth")?,
+            product_type_settings: match row.get::<_, Option<String>>("product_type_settings")? {
+                Some(json) => serde_json::from_str(&json).map_err(|err| {
+                    rusqlite::Error::FromSqlConversionFailure(
+                        row.as_ref().column_index("product_type_settings").unwrap(),
+                        Type::Text,
+                        Box::new(err),
+                    )
+                })?,
+                None => Vec::new(),
+            },
+            archive_passwords: match row.get::<_, Option<String>>("archive_passwords")? {
+                Some(json) => serde_json::from_str(&json).map_err(|err| {
+                    rusqlite::Error::FromSqlConversionFailure(
+                        row.as_ref().column_index("archive_passwords").unwrap(),
+                        Type::Text,
+                        Box::new(err),
+                    )
+                })?,
+                None => Vec::new(),
+            },
+            flatten_policy: row
+                .get_ref("flatten_policy")?
+                .as_str_or_null()?
+                .map(|policy| {
+                    <_>::from_str(policy).map_err(|err: strum::ParseError| {
+                        rusqlite::Error::FromSqlConversionFailure(
+                            row.as_ref().column_index("flatten_policy").unwrap(),
+                            Type::Text,
+                            Box::new(err),
+                        )
+                    })
+                })
+                .transpose()?,
+            keep_archives: row
+                .get_ref("keep_archives")?
+                .as_str_or_null()?
+                .map(|keep_archives| {
+                    <_>::from_str(keep_archives).map_err(|err: strum::ParseError| {
+                        rusqlite::Error::FromSqlConversionFailure(
+                            row.as_ref().column_index("keep_archives").unwrap(),
+                            Type::Text,
+                            Box::new(err),
+                        )
+                    })
+                })
+                .transpose()?,
+            archive_root_dir: row
+                .get::<_, Option<String>>("archive_root_dir")?
+                .map(|path| PathBuf::from(path)),
+            sync_concurrent_accounts: row.get("sync_concurrent_accounts")?,
+            sync_concurrent_pages: row.get("sync_concurrent_pages")?,
         })
     }
 }
@@ -28,13 +237,46 @@ CREATE TABLE IF NOT EXISTS settings (
 );"
     }
 
+    pub fn get_columns() -> &'static [(&'static str, &'static str)] {
+        &[
+            ("max_concurrent_downloads", "INTEGER"),
+            ("download_max_retries", "INTEGER"),
+            ("download_rate_limit", "INTEGER"),
+            ("download_windows", "TEXT"),
+            ("download_segments", "INTEGER"),
+            ("zip_filename_encoding", "TEXT"),
+            ("nested_extraction_depth", "INTEGER"),
+            ("product_type_settings", "TEXT"),
+            ("archive_passwords", "TEXT"),
+            ("flatten_policy", "TEXT"),
+            ("keep_archives", "TEXT"),
+            ("archive_root_dir", "TEXT"),
+            ("sync_concurrent_accounts", "INTEGER"),
+            ("sync_concurrent_pages", "INTEGER"),
+        ]
+    }
+
     pub fn get() -> Result<Self> {
         Ok(use_application()
             .connection()
             .prepare(
                 "
 SELECT
-    download_root_dir
+    download_root_dir,
+    max_concurrent_downloads,
+    download_max_retries,
+    download_rate_limit,
+    download_windows,
+    download_segments,
+    zip_filename_encoding,
+    nested_extraction_depth,
+    product_type_settings,
+    archive_passwords,
+    flatten_policy,
+    keep_archives,
+    archive_root_dir,
+    sync_concurrent_accounts,
+    sync_concurrent_pages
 FROM settings;",
             )?
             .query_row((), |row| Self::try_from(row))
@@ -53,16 +295,142 @@ DELETE FROM settings",
             .prepare(
                 "
 INSERT INTO settings (
-    download_root_dir
+    download_root_dir,
+    max_concurrent_downloads,
+    download_max_retries,
+    download_rate_limit,
+    download_windows,
+    download_segments,
+    zip_filename_encoding,
+    nested_extraction_depth,
+    product_type_settings,
+    archive_passwords,
+    flatten_policy,
+    keep_archives,
+    archive_root_dir,
+    sync_concurrent_accounts,
+    sync_concurrent_pages
 ) VALUES (
-    ?1
+    ?1,
+    ?2,
+    ?3,
+    ?4,
+    ?5,
+    ?6,
+    ?7,
+    ?8,
+    ?9,
+    ?10,
+    ?11,
+    ?12,
+    ?13,
+    ?14,
+    ?15
 )",
             )?
-            .insert(params![setting
-                .download_root_dir
-                .as_ref()
-                .map(|path| path.to_str().unwrap())])?;
+            .insert(params![
+                setting
+                    .download_root_dir
+                    .as_ref()
+                    .map(|path| path.to_str().unwrap()),
+                setting.max_concurrent_downloads,
+                setting.download_max_retries,
+                setting.download_rate_limit,
+                serde_json::to_string(&setting.download_windows)?,
+                setting.download_segments,
+                setting
+                    .zip_filename_encoding
+                    .map(|encoding| <_ as Into<&'static str>>::into(encoding)),
+                setting.nested_extraction_depth,
+                serde_json::to_string(&setting.product_type_settings)?,
+                serde_json::to_string(&setting.archive_passwords)?,
+                setting
+                    .flatten_policy
+                    .map(|policy| <_ as Into<&'static str>>::into(policy)),
+                setting
+                    .keep_archives
+                    .map(|keep_archives| <_ as Into<&'static str>>::into(keep_archives)),
+                setting
+                    .archive_root_dir
+                    .as_ref()
+                    .map(|path| path.to_str().unwrap()),
+                setting.sync_concurrent_accounts,
+                setting.sync_concurrent_pages,
+            ])?;
 
         Ok(())
     }
+
+    pub fn max_concurrent_downloads(&self) -> usize {
+        self.max_concurrent_downloads
+            .unwrap_or(DEFAULT_MAX_CONCURRENT_DOWNLOADS)
+            .max(1)
+    }
+
+    pub fn download_max_retries(&self) -> u32 {
+        self.download_max_retries
+            .unwrap_or(DEFAULT_DOWNLOAD_MAX_RETRIES)
+    }
+
+    pub fn download_segments(&self) -> usize {
+        self.download_segments
+            .unwrap_or(1)
+            .clamp(1, MAX_DOWNLOAD_SEGMENTS)
+    }
+
+    pub fn sync_concurrent_accounts(&self) -> usize {
+        self.sync_concurrent_accounts
+            .unwrap_or(DEFAULT_SYNC_CONCURRENT_ACCOUNTS)
+            .clamp(1, MAX_SYNC_CONCURRENT_ACCOUNTS)
+    }
+
+    pub fn sync_concurrent_pages(&self) -> usize {
+        self.sync_concurrent_pages
+            .unwrap_or(1)
+            .clamp(1, MAX_SYNC_CONCURRENT_PAGES)
+    }
+
+    pub fn download_rate_limit(&self) -> Option<u64> {
+        self.download_rate_limit.filter(|&limit| limit != 0)
+    }
+
+    /// Downloads may start at any time unless at least one window is configured.
+    pub fn is_download_window_open(&self, time: NaiveTime) -> bool {
+        self.download_windows.is_empty()
+            || self
+                .download_windows
+                .iter()
+                .any(|window| window.contains(time))
+    }
+
+    pub fn flatten_policy(&self) -> FlattenPolicy {
+        self.flatten_policy.unwrap_or_default()
+    }
+
+    /// Products of a type with its own depth use it, the others fall back to the global one.
+    pub fn nested_extraction_depth(&self, product_type: Option<&DLsiteProductType>) -> u32 {
+        self.get_product_type_setting(product_type)
+            .and_then(|setting| setting.nested_extraction_depth)
+            .or(self.nested_extraction_depth)
+            .unwrap_or(0)
+            .min(MAX_NESTED_EXTRACTION_DEPTH)
+    }
+
+    pub fn keep_archives(&self, product_type: Option<&DLsiteProductType>) -> KeepArchives {
+        self.get_product_type_setting(product_type)
+            .and_then(|setting| setting.keep_archives)
+            .or(self.keep_archives)
+            .unwrap_or_default()
+    }
+
+    fn get_product_type_setting(
+        &self,
+        product_type: Option<&DLsiteProductType>,
+    ) -> Option<&ProductTypeSetting> {
+        let product_type = product_type?.to_string();
+
+        self.product_type_settings
+            .iter()
+            .find(|setting| setting.product_type == product_type)
+    }
 }
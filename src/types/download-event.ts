@@ -1,11 +1,62 @@
 import type { ProductDownload } from "./product";
 
+export type DownloadPhase = "Downloading" | "Extracting" | "CleaningUp";
+
 export interface DownloadProgress {
   product_id: string;
+  phase: DownloadPhase;
   progress: number;
+  bytes_done: number;
+  bytes_total: number;
+  file_index: number;
+  file_count: number;
+  file_name: string;
+  speed: number;
+  eta?: number;
+}
+
+export interface DownloadWaiting {
+  product_id: string;
+  required: number;
+  available: number;
 }
 
 export interface DownloadComplete {
   product_id: string;
   download?: ProductDownload;
+  error?: DownloadFailure;
+}
+
+export interface DownloadFailure {
+  kind:
+    | "Authentication"
+    | "Rejected"
+    | "Network"
+    | "DiskFull"
+    | "FileSystem"
+    | "Archive"
+    | "PasswordRequired"
+    | "Database"
+    | "Cancelled"
+    | "Paused"
+    | "Other";
+  message: string;
+}
+
+export interface DownloadHistory {
+  id: number;
+  account_id: number;
+  product_id: string;
+  outcome:
+    | "InProgress"
+    | "Succeeded"
+    | "Failed"
+    | "Cancelled"
+    | "Paused"
+    | "Interrupted";
+  bytes_transferred: number;
+  error_kind?: string;
+  error_message?: string;
+  started_at: string;
+  ended_at?: string;
 }
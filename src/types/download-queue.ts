@@ -0,0 +1,16 @@
+export interface QueuedDownload {
+  id: number;
+  account_id: number;
+  product_id: string;
+  decompress: boolean;
+  priority: number;
+  order_index: number;
+  paused: boolean;
+  created_at: string;
+}
+
+export interface DownloadQueueStatus {
+  queue: QueuedDownload[];
+  running: string[];
+  waiting_for_space: string[];
+}
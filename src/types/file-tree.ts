@@ -0,0 +1,10 @@
+export interface FileTreeNode {
+  name: string;
+  kind: FileTreeNodeKind;
+  size: number;
+  password_required: boolean;
+  list_error: string | null;
+  children: FileTreeNode[];
+}
+
+export type FileTreeNodeKind = "File" | "Directory" | "Archive";
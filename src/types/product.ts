@@ -3,11 +3,19 @@ import type { Account } from "./account";
 export interface Product {
   id: number;
   account: Account;
+  owners: ProductOwner[];
   product: DLsiteProduct;
   download?: ProductDownload;
+  revoked_at?: string;
   json: string;
 }
 
+export interface ProductOwner {
+  account_id: number;
+  username: string;
+  purchased_at: string;
+}
+
 export interface ProductDownload {
   id: number;
   path: string;
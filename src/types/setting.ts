@@ -1,7 +1,47 @@
-import type { DLsiteProductLocalizedString } from "./product";
+import type {
+  DLsiteProductLocalizedString,
+  DLsiteProductType,
+} from "./product";
 
 export interface Setting {
   download_root_dir: string;
+  max_concurrent_downloads?: number;
+  download_max_retries?: number;
+  download_rate_limit?: number;
+  download_windows?: DownloadWindow[];
+  download_segments?: number;
+  zip_filename_encoding?: ZipFilenameEncoding;
+  nested_extraction_depth?: number;
+  product_type_settings?: ProductTypeSetting[];
+  archive_passwords?: string[];
+  flatten_policy?: FlattenPolicy;
+  keep_archives?: KeepArchives;
+  archive_root_dir?: string;
+  sync_concurrent_accounts?: number;
+  sync_concurrent_pages?: number;
+}
+
+export interface ProductTypeSetting {
+  product_type: DLsiteProductType;
+  nested_extraction_depth?: number;
+  keep_archives?: KeepArchives;
+}
+
+export type ZipFilenameEncoding =
+  | "Utf8"
+  | "Cp932"
+  | "Cp437"
+  | "Gbk"
+  | "Big5"
+  | "EucKr";
+
+export type FlattenPolicy = "Never" | "SingleDir" | "MatchingDir";
+
+export type KeepArchives = "Never" | "ProductFolder" | "ArchiveRoot";
+
+export interface DownloadWindow {
+  start: string;
+  end: string;
 }
 
 export interface DisplayLanguageSetting {